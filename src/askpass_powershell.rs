@@ -0,0 +1,402 @@
+use crate::credential::{Credential, CredentialEntry, CredentialStore, Metadata, Target};
+use crate::credential_powershell::{
+    StoredEntry, deleter_type, malformed, reader_type, target_name, writer_type,
+};
+use crate::dialog_powershell::credui_type;
+use crate::powershell;
+use crate::secret::Secret;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+/// Looks up the cached answer, and shows the dialog and saves what the user
+/// entered if there is none, in a single script. A cached answer is only
+/// reported: whether it has expired, and what to record of its use, is
+/// decided by [`Target::get_credential`] as for any other store.
+pub(crate) const ASKPASS_SCRIPT: &str = concat!(
+    reader_type!(),
+    writer_type!(),
+    deleter_type!(),
+    credui_type!(),
+    r#"
+$result = @{ status = 'cancelled' }
+
+if ($data.forget) {
+    # The cached passphrase was rejected: drop it so it is not offered again
+    try {
+        foreach ($name in $data.names) { [void][CredentialDeleter]::Delete($name) }
+    } catch {
+        $result.warning = "Failed to delete stale credential: " + $_
+    }
+} else {
+    foreach ($name in $data.names) {
+        try {
+            $credential = [CredentialManager]::Read($name)
+        } catch {
+            # Prompt instead of failing when the cache cannot be read
+            $result.warning = "Failed to read cached credential: " + $_
+            break
+        }
+        if ($credential -ne $null) {
+            $result.status = 'found'
+            $result.entry = ConvertTo-Entry $credential
+            break
+        }
+    }
+}
+
+if ($result.status -ne 'found') {
+    $save = $false
+    $password = [CredUI]::Prompt($data.caption, $data.prompt, "", $true, $data.retry, [ref]$save)
+    if ($password -ne $null) {
+        $result.status = 'entered'
+        $result.password = $password
+        if ($save) {
+            try {
+                [CredentialWriter]::Write($data.target, $password, '', $data.comment, [string[]]$data.attributes)
+            } catch {
+                $result.warning = "Failed to save credential: " + $_
+            }
+        }
+    }
+}
+
+$result | ConvertTo-Json -Compress -Depth 4
+"#
+);
+
+/// A password prompt to answer from the cache or the dialog.
+pub struct AskpassRequest<'a> {
+    /// Credential the answer is cached under
+    pub target: &'a Target,
+    /// Title of the dialog
+    pub caption: &'a str,
+    pub prompt: &'a str,
+    /// The cached answer was rejected: delete it and report the failure in
+    /// the dialog
+    pub retry: bool,
+}
+
+#[derive(Serialize)]
+struct Input<'a> {
+    target: String,
+    /// Names to look the answer up under, in order; none to go straight to
+    /// the dialog
+    names: Vec<String>,
+    forget: bool,
+    caption: &'a str,
+    prompt: &'a str,
+    retry: bool,
+    comment: &'a str,
+    attributes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    status: String,
+    password: Option<Secret>,
+    /// The cached credential, when the status is `found`
+    entry: Option<StoredEntry>,
+    warning: Option<String>,
+}
+
+/// The store as the script left it: the credential it found is stored
+/// under `name`, and the names it looked up before are not. Changes go to
+/// `store`.
+struct Found<'a> {
+    store: &'a dyn CredentialStore,
+    name: String,
+    credential: RefCell<Option<Credential>>,
+}
+
+impl CredentialStore for Found<'_> {
+    fn name(&self) -> &'static str {
+        self.store.name()
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Credential>> {
+        if name == self.name {
+            Ok(self.credential.borrow_mut().take())
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn store(&self, name: &str, secret: &str, metadata: &Metadata) -> Result<()> {
+        self.store.store(name, secret, metadata)
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        self.store.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<CredentialEntry>> {
+        self.store.list()
+    }
+}
+
+/// Lookup, dialog and store in one `powershell.exe` run, used when both the
+/// credential store and the dialog go through PowerShell.
+pub struct PowerShellAskpass {
+    program: PathBuf,
+}
+
+impl Default for PowerShellAskpass {
+    fn default() -> Self {
+        PowerShellAskpass {
+            program: PathBuf::from("powershell.exe"),
+        }
+    }
+}
+
+impl PowerShellAskpass {
+    /// Returns the answer, or None if the user cancelled. Updates to a cached
+    /// answer are written to `store`, which only happens when its metadata
+    /// changes. An expired answer takes a second run to show the dialog.
+    pub fn answer(
+        &self,
+        store: &dyn CredentialStore,
+        request: &AskpassRequest,
+    ) -> Result<Option<Secret>> {
+        let target = request.target;
+        let names = std::iter::once(target.name.as_str())
+            .chain(target.path.as_deref())
+            .map(target_name)
+            .collect();
+        let mut output = self.run(request, names)?;
+
+        if output.status == "found" {
+            let entry = output
+                .entry
+                .ok_or_else(|| malformed("Missing credential in PowerShell output"))?;
+            let found = Found {
+                store,
+                name: entry
+                    .name()
+                    .ok_or_else(|| malformed("Unexpected credential in PowerShell output"))?
+                    .to_string(),
+                credential: RefCell::new(Some(entry.into_credential()?)),
+            };
+            match target.get_credential(&found) {
+                Ok(Some(credential)) => return Ok(Some(credential.secret)),
+                Ok(None) => {}
+                Err(e) => eprintln!("Warning: Failed to read cached credential: {}", e),
+            }
+            output = self.run(request, Vec::new())?;
+        }
+
+        match output.status.as_str() {
+            "entered" => Ok(output.password),
+            _ => Ok(None),
+        }
+    }
+
+    fn run(&self, request: &AskpassRequest, names: Vec<String>) -> Result<Output> {
+        let metadata = request.target.metadata.created_now();
+        let input = Input {
+            target: target_name(&request.target.name),
+            names,
+            forget: request.retry,
+            caption: request.caption,
+            prompt: request.prompt,
+            retry: request.retry,
+            comment: metadata.comment(),
+            attributes: metadata
+                .attributes()
+                .into_iter()
+                .flat_map(|(keyword, value)| [keyword.to_string(), value])
+                .collect(),
+        };
+
+        let output = powershell::execute(
+            &self.program,
+            &["-NoProfile", "-NonInteractive"],
+            ASKPASS_SCRIPT,
+            &input,
+        )?;
+        // The output is not quoted in errors: it may hold the password
+        let output: Output = serde_json::from_str(output.expose())
+            .map_err(|_| malformed("Unexpected PowerShell output"))?;
+        if let Some(warning) = &output.warning {
+            eprintln!("Warning: {}", warning);
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::credential::memory::MemoryStore;
+    use crate::credential::now;
+    use crate::powershell::stand_in::StandIn;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    fn askpass(stand_in: &StandIn) -> PowerShellAskpass {
+        PowerShellAskpass {
+            program: stand_in.program.clone(),
+        }
+    }
+
+    /// A key keyed by fingerprint, whose passphrase may still be stored by
+    /// path.
+    fn target() -> Target {
+        Target {
+            name: "SHA256:abc".to_string(),
+            path: Some("/home/user/.ssh/id_ed25519".to_string()),
+            metadata: Metadata {
+                key_path: Some("/home/user/.ssh/id_ed25519".to_string()),
+                fingerprint: Some("SHA256:abc".to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The script's output when it finds `secret` under `name`.
+    fn found(name: &str, attributes: serde_json::Value) -> String {
+        serde_json::json!({
+            "status": "found",
+            "entry": {
+                "name": format!("winaskpass:{}", name),
+                "comment": "/home/user/.ssh/id_ed25519",
+                "attributes": attributes,
+                "secret": STANDARD.encode("secret"),
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_script_is_well_formed() {
+        assert!(crate::powershell::parse(ASKPASS_SCRIPT).errors.is_empty());
+    }
+
+    #[test]
+    fn test_fresh_cached_hit_writes_nothing() {
+        let now = now().to_string();
+        let stand_in = StandIn::new(
+            "askpass-cached",
+            &found(
+                "SHA256:abc",
+                serde_json::json!({ "version": "1", "created": now, "last_recorded_use": now }),
+            ),
+        );
+        let store = MemoryStore::default();
+        let target = target();
+        let request = AskpassRequest {
+            target: &target,
+            caption: "SSH Key Passphrase",
+            prompt: "Enter passphrase for /home/user/.ssh/id_ed25519: ",
+            retry: false,
+        };
+
+        let answer = askpass(&stand_in).answer(&store, &request).unwrap();
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("secret"));
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(stand_in.requests().len(), 1);
+
+        let input = stand_in.input();
+        assert_eq!(input["target"], "winaskpass:SHA256:abc");
+        assert_eq!(
+            input["names"],
+            serde_json::json!([
+                "winaskpass:SHA256:abc",
+                "winaskpass:/home/user/.ssh/id_ed25519"
+            ])
+        );
+        assert_eq!(input["forget"], false);
+        assert!(!stand_in.argv().contains("id_ed25519"));
+    }
+
+    #[test]
+    fn test_legacy_hit_migrated_in_rust() {
+        let stand_in = StandIn::new(
+            "askpass-legacy",
+            &found("/home/user/.ssh/id_ed25519", serde_json::json!({})),
+        );
+        let store = MemoryStore::default();
+        let target = target();
+        let request = AskpassRequest {
+            target: &target,
+            caption: "SSH Key Passphrase",
+            prompt: "Enter passphrase for /home/user/.ssh/id_ed25519: ",
+            retry: false,
+        };
+
+        let answer = askpass(&stand_in).answer(&store, &request).unwrap();
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("secret"));
+        let credential = store.get("SHA256:abc").unwrap().unwrap();
+        assert_eq!(credential.secret.expose(), "secret");
+        assert_eq!(
+            credential.metadata.fingerprint.as_deref(),
+            Some("SHA256:abc")
+        );
+    }
+
+    #[test]
+    fn test_expired_hit_shows_dialog() {
+        let stand_in = StandIn::new(
+            "askpass-expired",
+            &found(
+                "SHA256:abc",
+                serde_json::json!({ "created": "1000", "ttl": "60" }),
+            ),
+        );
+        let store = MemoryStore::default();
+        let target = target();
+        let request = AskpassRequest {
+            target: &target,
+            caption: "SSH Key Passphrase",
+            prompt: "Enter passphrase for /home/user/.ssh/id_ed25519: ",
+            retry: false,
+        };
+
+        // The stand-in finds it again, which counts as no answer
+        assert!(
+            askpass(&stand_in)
+                .answer(&store, &request)
+                .unwrap()
+                .is_none()
+        );
+        let requests = stand_in.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1]["data"]["names"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_cancelled_dialog() {
+        let stand_in = StandIn::new("askpass-cancelled", r#"{"status":"cancelled"}"#);
+        let target = Target::named("/home/user/.ssh/id_rsa".to_string(), Metadata::default());
+        let request = AskpassRequest {
+            target: &target,
+            caption: "SSH Key Passphrase",
+            prompt: "Bad passphrase, try again for /home/user/.ssh/id_rsa: ",
+            retry: true,
+        };
+
+        let answer = askpass(&stand_in).answer(&MemoryStore::default(), &request);
+        assert!(answer.unwrap().is_none());
+        let input = stand_in.input();
+        assert_eq!(input["forget"], true);
+        assert_eq!(input["caption"], "SSH Key Passphrase");
+        assert_eq!(input["retry"], true);
+    }
+
+    #[test]
+    fn test_unexpected_output_is_an_error() {
+        let stand_in = StandIn::new("askpass-garbage", "not json");
+        let target = Target::named("/home/user/.ssh/id_rsa".to_string(), Metadata::default());
+        let request = AskpassRequest {
+            target: &target,
+            caption: "SSH Key Passphrase",
+            prompt: "Enter passphrase for /home/user/.ssh/id_rsa: ",
+            retry: false,
+        };
+
+        let answer = askpass(&stand_in).answer(&MemoryStore::default(), &request);
+        assert!(answer.is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! `winaskpass cargo-credential`: cargo's credential provider protocol, so
+//! registry tokens are kept in the credential store instead of
+//! `~/.cargo/credentials.toml`.
+
+use crate::credential::{CredentialStore, Metadata, Target};
+use crate::dialog::Prompter;
+use crate::error::Error;
+use crate::secret::Secret;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// Registry tokens are stored apart from SSH keys under this prefix.
+const TARGET_PREFIX: &str = "cargo:";
+
+#[derive(Deserialize)]
+struct Request {
+    v: u32,
+    registry: Registry,
+    kind: String,
+    /// Given to `login` when passed to `cargo login` on the command line
+    token: Option<Secret>,
+}
+
+#[derive(Deserialize)]
+struct Registry {
+    #[serde(rename = "index-url")]
+    index_url: String,
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+enum Response {
+    Ok(Success),
+    Err(Failure),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum Success {
+    Get {
+        token: Secret,
+        /// Cargo may keep the token until it exits
+        cache: &'static str,
+        /// The same token is used for every operation
+        operation_independent: bool,
+    },
+    Login,
+    Logout,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum Failure {
+    NotFound,
+    OperationNotSupported,
+    Other {
+        message: String,
+        #[serde(rename = "caused-by")]
+        caused_by: Vec<String>,
+    },
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(error: anyhow::Error) -> Failure {
+        Failure::Other {
+            message: error.to_string(),
+            caused_by: error
+                .chain()
+                .skip(1)
+                .map(|cause| cause.to_string())
+                .collect(),
+        }
+    }
+}
+
+fn target(registry: &Registry) -> Target {
+    Target::named(
+        format!("{}{}", TARGET_PREFIX, registry.index_url),
+        Metadata::default(),
+    )
+}
+
+/// Says hello and answers requests until the end of input. The dialog is
+/// only looked for when `cargo login` is not given a token.
+pub fn serve(
+    store: &dyn CredentialStore,
+    prompter: &dyn Fn() -> Result<Box<dyn Prompter>>,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<()> {
+    writeln!(output, r#"{{"v":[1]}}"#)?;
+    output.flush()?;
+
+    loop {
+        // A login request holds the token
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = Secret::from(line);
+        if line.expose().trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(line.expose()) {
+            Ok(request) if request.v == 1 => handle(store, prompter, request),
+            Ok(request) => {
+                Response::Err(anyhow::anyhow!("Unsupported protocol version {}", request.v).into())
+            }
+            Err(e) => Response::Err(anyhow::Error::new(e).context("Invalid request").into()),
+        };
+        serde_json::to_writer(&mut output, &response)?;
+        writeln!(output)?;
+        output.flush()?;
+    }
+}
+
+fn handle(
+    store: &dyn CredentialStore,
+    prompter: &dyn Fn() -> Result<Box<dyn Prompter>>,
+    request: Request,
+) -> Response {
+    let target = target(&request.registry);
+    let result = match request.kind.as_str() {
+        "get" => match target.get(store) {
+            Ok(Some(token)) => Ok(Success::Get {
+                token,
+                cache: "session",
+                operation_independent: true,
+            }),
+            Ok(None) => Err(Failure::NotFound),
+            Err(e) => Err(e.into()),
+        },
+        "login" => login(store, prompter, &request, &target).map_err(Failure::from),
+        "logout" => match store.delete(&target.name) {
+            Ok(true) => Ok(Success::Logout),
+            Ok(false) => Err(Failure::NotFound),
+            Err(e) => Err(e.into()),
+        },
+        _ => Err(Failure::OperationNotSupported),
+    };
+    match result {
+        Ok(success) => Response::Ok(success),
+        Err(failure) => Response::Err(failure),
+    }
+}
+
+fn login(
+    store: &dyn CredentialStore,
+    prompter: &dyn Fn() -> Result<Box<dyn Prompter>>,
+    request: &Request,
+    target: &Target,
+) -> Result<Success> {
+    let prompted;
+    let token = match &request.token {
+        Some(token) => token,
+        None => {
+            let registry = request
+                .registry
+                .name
+                .as_deref()
+                .unwrap_or(&request.registry.index_url);
+            let prompt = format!("Enter the API token for registry {}:", registry);
+            prompted = prompter()?
+                .prompt_password("Cargo Registry Token", &prompt, false, false)?
+                .ok_or(Error::Cancelled)?;
+            &prompted.password
+        }
+    };
+    if token.expose().trim().is_empty() {
+        anyhow::bail!("The token is empty");
+    }
+    target.store(store, token.expose().trim())?;
+    Ok(Success::Login)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::memory::MemoryStore;
+    use crate::dialog::scripted::ScriptedPrompter;
+
+    const INDEX: &str = "sparse+https://crates.example.com/index/";
+
+    fn request(kind: &str, extra: &str) -> String {
+        format!(
+            r#"{{"v":1,"registry":{{"index-url":"{}","name":"example"}},"kind":"{}"{},"args":[]}}"#,
+            INDEX, kind, extra
+        )
+    }
+
+    /// Sends `requests` and returns the responses after the hello.
+    fn session(
+        store: &MemoryStore,
+        password: Option<&'static str>,
+        requests: &[String],
+    ) -> Vec<serde_json::Value> {
+        let prompter = move || -> Result<Box<dyn Prompter>> {
+            Ok(Box::new(ScriptedPrompter {
+                password: password.map(|password| (password, false)),
+                ..Default::default()
+            }))
+        };
+        let input: String = requests.iter().map(|r| format!("{}\n", r)).collect();
+        let mut output = Vec::new();
+        serve(store, &prompter, input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some(r#"{"v":[1]}"#));
+        lines
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_login_get_logout() {
+        let store = MemoryStore::default();
+        let responses = session(
+            &store,
+            None,
+            &[
+                request("login", r#","token":"cio-token","login-url":null"#),
+                request(
+                    "get",
+                    r#","operation":"publish","name":"foo","vers":"0.1.0","cksum":"abc""#,
+                ),
+                request("logout", ""),
+                request("get", r#","operation":"read""#),
+                request("logout", ""),
+            ],
+        );
+        assert_eq!(
+            responses,
+            [
+                serde_json::json!({"Ok": {"kind": "login"}}),
+                serde_json::json!({"Ok": {
+                    "kind": "get",
+                    "token": "cio-token",
+                    "cache": "session",
+                    "operation_independent": true,
+                }}),
+                serde_json::json!({"Ok": {"kind": "logout"}}),
+                serde_json::json!({"Err": {"kind": "not-found"}}),
+                serde_json::json!({"Err": {"kind": "not-found"}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_login_asks_with_dialog() {
+        let store = MemoryStore::default();
+        let responses = session(&store, Some("typed-token\n"), &[request("login", "")]);
+        assert_eq!(responses, [serde_json::json!({"Ok": {"kind": "login"}})]);
+        let name = format!("cargo:{}", INDEX);
+        assert_eq!(
+            store.get(&name).unwrap().unwrap().secret.expose(),
+            "typed-token"
+        );
+
+        let store = MemoryStore::default();
+        let responses = session(&store, None, &[request("login", "")]);
+        assert_eq!(responses[0]["Err"]["kind"], "other");
+        assert_eq!(responses[0]["Err"]["message"], "Cancelled by the user");
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_requests() {
+        let store = MemoryStore::default();
+        let responses = session(
+            &store,
+            None,
+            &[request("frobnicate", ""), r#"{"v":2}"#.to_string()],
+        );
+        assert_eq!(
+            responses[0],
+            serde_json::json!({"Err": {"kind": "operation-not-supported"}})
+        );
+        assert_eq!(responses[1]["Err"]["kind"], "other");
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::{Context, Result};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Reads a setting from the `WINASKPASS_<NAME>` environment variable, falling
+/// back to a `name = value` line in the config file.
+pub fn get(name: &str) -> Option<String> {
+    if let Ok(value) = env::var(format!("WINASKPASS_{}", name.to_ascii_uppercase())) {
+        return Some(value);
+    }
+
+    let contents = fs::read_to_string(config_path()?).ok()?;
+    lookup(&contents, name)
+}
+
+/// Like [`get`], but a `name <scope> = value` line in the config file for
+/// which `in_scope(scope)` holds takes precedence, e.g.
+/// `ttl ~/.ssh/id_work = 1d`.
+pub fn get_scoped(name: &str, in_scope: impl Fn(&str) -> bool) -> Option<String> {
+    let scoped = config_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| lookup_scoped(&contents, name, in_scope));
+    scoped.or_else(|| get(name))
+}
+
+/// Parses a duration such as `30d`, `12h`, `90m` or `3600` (seconds).
+/// `never` and `0` mean no duration at all.
+pub fn parse_duration(value: &str) -> Result<Option<u64>> {
+    let value = value.trim();
+    if value == "never" {
+        return Ok(None);
+    }
+
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => anyhow::bail!("Invalid duration: {}", value),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration: {}", value))?;
+    let seconds = number
+        .checked_mul(multiplier)
+        .with_context(|| format!("Duration too long: {}", value))?;
+    Ok(Some(seconds).filter(|seconds| *seconds > 0))
+}
+
+/// `$XDG_CONFIG_HOME/winaskpass/config`, `~/.config/winaskpass/config` or
+/// `%APPDATA%\winaskpass\config`.
+pub fn config_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("winaskpass").join("config"))
+}
+
+fn lookup(contents: &str, name: &str) -> Option<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, value)| value.trim().to_string())
+}
+
+fn lookup_scoped(contents: &str, name: &str, in_scope: impl Fn(&str) -> bool) -> Option<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| {
+            key.trim()
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(char::is_whitespace))
+                .is_some_and(|scope| in_scope(scope.trim()))
+        })
+        .map(|(_, value)| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let contents = "# winaskpass\nstore = powershell\nkey_id=fingerprint\n";
+        assert_eq!(lookup(contents, "store").as_deref(), Some("powershell"));
+        assert_eq!(lookup(contents, "key_id").as_deref(), Some("fingerprint"));
+        assert_eq!(lookup(contents, "missing"), None);
+    }
+
+    #[test]
+    fn test_lookup_skips_comments() {
+        let contents = "# store = native\nstore = powershell\n";
+        assert_eq!(lookup(contents, "store").as_deref(), Some("powershell"));
+    }
+
+    #[test]
+    fn test_lookup_scoped() {
+        let contents = "ttl = 30d\nttl ~/.ssh/id_work = 1d\nttl_other x = 2d\n";
+        let scoped = |scope: &str| lookup_scoped(contents, "ttl", |s| s == scope);
+        assert_eq!(scoped("~/.ssh/id_work").as_deref(), Some("1d"));
+        assert_eq!(scoped("~/.ssh/id_rsa"), None);
+        assert_eq!(scoped("x"), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30d").unwrap(), Some(30 * 86400));
+        assert_eq!(parse_duration("12h").unwrap(), Some(12 * 3600));
+        assert_eq!(parse_duration("2w").unwrap(), Some(14 * 86400));
+        assert_eq!(parse_duration(" 3600 ").unwrap(), Some(3600));
+        assert_eq!(parse_duration("0").unwrap(), None);
+        assert_eq!(parse_duration("never").unwrap(), None);
+        assert!(parse_duration("30 days").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("18446744073709551615w").is_err());
+    }
+}
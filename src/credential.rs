@@ -0,0 +1,728 @@
+use crate::config;
+use crate::error::Error;
+use crate::key;
+use crate::prompt::PromptKind;
+use crate::secret::Secret;
+use anyhow::Result;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the metadata layout written with each credential. Entries
+/// written before metadata existed read back as version 0.
+pub const METADATA_VERSION: u32 = 1;
+
+/// Longest Comment Credential Manager accepts, in UTF-16 units
+/// (CRED_MAX_STRING_LENGTH).
+const MAX_COMMENT_LENGTH: usize = 256;
+
+/// How long a recorded use stands for later ones. Looking a secret up only
+/// writes it back once this has passed, since every write replaces the whole
+/// credential, so uses are counted by the hour.
+pub const USE_RECORD_INTERVAL: u64 = 60 * 60;
+
+/// What is known about a stored credential besides its secret. The key path
+/// is kept in the credential's Comment, everything else in its Attributes.
+#[derive(Clone, Default, Debug, PartialEq, Serialize)]
+pub struct Metadata {
+    pub version: u32,
+    /// Unix time the secret was stored
+    pub created: Option<u64>,
+    /// Unix time of the last use that was recorded. Later uses within
+    /// [`USE_RECORD_INTERVAL`] of it are not, so the secret may have been
+    /// used up to an hour after this.
+    pub last_recorded_use: Option<u64>,
+    /// Hours in which the secret answered a prompt: the uses recorded, at
+    /// most one per [`USE_RECORD_INTERVAL`]
+    pub use_hours: u64,
+    pub key_path: Option<String>,
+    pub key_type: Option<String>,
+    pub fingerprint: Option<String>,
+    /// Program that prompted for the secret, e.g. "ssh-add"
+    pub program: Option<String>,
+    /// User name the secret belongs to, e.g. for a git remote
+    pub username: Option<String>,
+    /// Seconds after its creation the secret expires, as configured when it
+    /// was stored. The ttl configured at lookup takes its place.
+    pub ttl: Option<u64>,
+}
+
+impl Metadata {
+    /// Marks the metadata as describing a secret stored just now.
+    pub fn created_now(&self) -> Metadata {
+        Metadata {
+            version: METADATA_VERSION,
+            created: Some(now()),
+            last_recorded_use: None,
+            use_hours: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Whether a use now is worth writing back: the last recorded one is
+    /// more than [`USE_RECORD_INTERVAL`] ago, or there is none.
+    pub fn use_is_stale(&self) -> bool {
+        self.last_recorded_use
+            .is_none_or(|recorded| now().saturating_sub(recorded) >= USE_RECORD_INTERVAL)
+    }
+
+    /// Records that the secret answered a prompt, starting a new hour of
+    /// use.
+    pub fn touch(&mut self) {
+        self.last_recorded_use = Some(now());
+        self.use_hours += 1;
+    }
+
+    /// Unix time the secret expires, if it does, counting from its creation.
+    /// An expiry past the end of time is none.
+    pub fn expires(&self) -> Option<u64> {
+        self.created?.checked_add(self.ttl?)
+    }
+
+    /// Gives an entry stored without a creation time, i.e. before metadata
+    /// existed, one now, so that its ttl starts running. Returns whether it
+    /// had none.
+    pub fn start_clock(&mut self) -> bool {
+        let missing = self.created.is_none();
+        if missing {
+            self.created = Some(now());
+        }
+        missing
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires().is_some_and(|expires| expires <= now())
+    }
+
+    /// Comment to store: the key path, cut short where Credential Manager
+    /// would refuse it. The path is only shown, so a truncated one does no
+    /// harm.
+    pub fn comment(&self) -> &str {
+        let key_path = self.key_path.as_deref().unwrap_or_default();
+        let mut length = 0;
+        for (index, c) in key_path.char_indices() {
+            length += c.len_utf16();
+            if length > MAX_COMMENT_LENGTH {
+                return &key_path[..index];
+            }
+        }
+        key_path
+    }
+
+    /// Attributes to store, as keyword and value pairs. They are always
+    /// written in the current layout.
+    pub fn attributes(&self) -> Vec<(&'static str, String)> {
+        let mut attributes = vec![
+            ("version", METADATA_VERSION.to_string()),
+            ("use_hours", self.use_hours.to_string()),
+        ];
+        let optional = [
+            ("created", self.created.map(|t| t.to_string())),
+            (
+                "last_recorded_use",
+                self.last_recorded_use.map(|t| t.to_string()),
+            ),
+            ("key_type", self.key_type.clone()),
+            ("fingerprint", self.fingerprint.clone()),
+            ("program", self.program.clone()),
+            ("username", self.username.clone()),
+            ("ttl", self.ttl.map(|t| t.to_string())),
+        ];
+        for (keyword, value) in optional {
+            if let Some(value) = value {
+                attributes.push((keyword, value));
+            }
+        }
+        attributes
+    }
+
+    /// Rebuilds the metadata from a credential's Comment and Attributes,
+    /// ignoring attributes it does not know.
+    pub fn from_parts<K, V>(
+        comment: Option<String>,
+        attributes: impl IntoIterator<Item = (K, V)>,
+    ) -> Metadata
+    where
+        K: AsRef<str>,
+        V: Into<String>,
+    {
+        let mut metadata = Metadata {
+            key_path: comment.filter(|c| !c.is_empty()),
+            ..Default::default()
+        };
+        for (keyword, value) in attributes {
+            let value: String = value.into();
+            match keyword.as_ref() {
+                "version" => metadata.version = value.parse().unwrap_or_default(),
+                "created" => metadata.created = value.parse().ok(),
+                "last_recorded_use" => metadata.last_recorded_use = value.parse().ok(),
+                "use_hours" => metadata.use_hours = value.parse().unwrap_or_default(),
+                "key_type" => metadata.key_type = Some(value),
+                "fingerprint" => metadata.fingerprint = Some(value),
+                "program" => metadata.program = Some(value),
+                "username" => metadata.username = Some(value),
+                "ttl" => metadata.ttl = value.parse().ok(),
+                _ => {}
+            }
+        }
+        metadata
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+pub struct Credential {
+    pub secret: Secret,
+    pub metadata: Metadata,
+}
+
+#[derive(Serialize)]
+pub struct CredentialEntry {
+    pub name: String,
+    #[serde(flatten)]
+    pub metadata: Metadata,
+}
+
+/// Name prefixes of the credentials stored by the helper modes, with the
+/// kind of credential each holds. Other names belong to SSH prompts: a key
+/// path or fingerprint, a token label or a remote.
+const KINDS: [(&str, &str); 5] = [
+    ("git:", "git"),
+    ("docker:", "docker"),
+    ("cargo:", "cargo"),
+    ("gpg:", "gpg"),
+    ("systemd:", "systemd"),
+];
+
+impl CredentialEntry {
+    /// What the credential is for: `ssh`, or the helper mode that stored it.
+    pub fn kind(&self) -> &'static str {
+        KINDS
+            .iter()
+            .find(|(prefix, _)| self.name.starts_with(prefix))
+            .map_or("ssh", |(_, kind)| kind)
+    }
+}
+
+/// A place to keep secrets, addressed by name.
+pub trait CredentialStore {
+    fn name(&self) -> &'static str;
+
+    fn get(&self, name: &str) -> Result<Option<Credential>>;
+
+    fn store(&self, name: &str, secret: &str, metadata: &Metadata) -> Result<()>;
+
+    /// Removes the stored credential, returning whether there was one to remove.
+    fn delete(&self, name: &str) -> Result<bool>;
+
+    fn list(&self) -> Result<Vec<CredentialEntry>>;
+}
+
+const DEFAULT_STORE: &str = if cfg!(feature = "native") {
+    "native"
+} else {
+    "powershell"
+};
+
+/// Opens the store selected by the `store` setting, defaulting to the native
+/// backend when it is compiled in.
+pub fn open() -> Result<Box<dyn CredentialStore>> {
+    let backend = config::get("store").unwrap_or_else(|| DEFAULT_STORE.to_string());
+    match backend.as_str() {
+        #[cfg(feature = "native")]
+        "native" => Ok(Box::new(crate::credential_native::NativeStore)),
+        #[cfg(feature = "powershell")]
+        "powershell" => Ok(Box::new(
+            crate::credential_powershell::PowerShellStore::default(),
+        )),
+        other => Err(Error::Unavailable(format!(
+            "Unknown or unavailable credential store: {}",
+            other
+        ))
+        .into()),
+    }
+}
+
+/// Credential the answer to a prompt is cached under.
+pub struct Target {
+    pub name: String,
+    /// Key path of a fingerprint-keyed credential: the name a not yet
+    /// migrated credential is still stored under
+    pub path: Option<String>,
+    /// Metadata stored with a newly saved answer
+    pub metadata: Metadata,
+}
+
+impl Target {
+    pub fn for_prompt(kind: &PromptKind) -> Option<Target> {
+        let mut target = Target::without_ttl(kind)?;
+        target.metadata.ttl = target.configured_ttl().flatten();
+        Some(target)
+    }
+
+    fn without_ttl(kind: &PromptKind) -> Option<Target> {
+        let metadata = Metadata {
+            program: prompting_program(),
+            ..Default::default()
+        };
+        match *kind {
+            PromptKind::KeyPassphrase { key_path, .. }
+            | PromptKind::BadPassphrase { key_path, .. } => {
+                let id = key::key_id(key_path);
+                let path = id.is_fingerprint().then(|| id.path.clone());
+                Some(Target {
+                    name: id.target,
+                    path,
+                    metadata: Metadata {
+                        key_path: Some(id.path),
+                        key_type: id.public_key.as_ref().map(|k| k.key_type.clone()),
+                        fingerprint: id.public_key.map(|k| k.fingerprint),
+                        ..metadata
+                    },
+                })
+            }
+            _ => kind.credential_target().map(|name| Target {
+                name: name.to_string(),
+                path: None,
+                metadata,
+            }),
+        }
+    }
+
+    /// Passphrase gpg-agent asks pinentry for, by the keygrip of its key.
+    pub fn for_keygrip(keygrip: &str) -> Target {
+        Target::named(format!("gpg:{}", keygrip), Metadata::default())
+    }
+
+    /// Credential stored under `name` by one of the credential helper modes.
+    pub fn named(name: String, metadata: Metadata) -> Target {
+        let mut target = Target {
+            name,
+            path: None,
+            metadata: Metadata {
+                program: prompting_program(),
+                ..metadata
+            },
+        };
+        target.metadata.ttl = target.configured_ttl().flatten();
+        target
+    }
+
+    /// The ttl configured for this credential, see [`configured_ttl`].
+    pub fn configured_ttl(&self) -> Option<Option<u64>> {
+        configured_ttl(&CredentialEntry {
+            name: self.name.clone(),
+            metadata: self.metadata.clone(),
+        })
+    }
+
+    /// Looks up the cached answer, recording the use in its metadata. An
+    /// expired answer is deleted and treated as missing.
+    pub fn get(&self, store: &dyn CredentialStore) -> Result<Option<Secret>> {
+        Ok(self
+            .get_credential(store)?
+            .map(|credential| credential.secret))
+    }
+
+    /// Like [`Target::get`], with the metadata stored along.
+    pub fn get_credential(&self, store: &dyn CredentialStore) -> Result<Option<Credential>> {
+        let ttl = self.configured_ttl();
+        if let Some(mut credential) = store.get(&self.name)? {
+            let mut changed = false;
+            if let Some(ttl) = ttl
+                && credential.metadata.ttl != ttl
+            {
+                credential.metadata.ttl = ttl;
+                changed = true;
+            }
+            changed |= credential.metadata.start_clock();
+            if credential.metadata.is_expired() {
+                store.delete(&self.name)?;
+                return Ok(None);
+            }
+            if credential.metadata.use_is_stale() {
+                credential.metadata.touch();
+                changed = true;
+            }
+            // Rewriting stores the secret again, so only do it when there is
+            // something new to keep
+            if changed
+                && let Err(e) =
+                    store.store(&self.name, credential.secret.expose(), &credential.metadata)
+            {
+                eprintln!("Warning: Failed to update credential metadata: {}", e);
+            }
+            return Ok(Some(credential));
+        }
+
+        // Re-key a credential stored by path before fingerprint mode was enabled
+        if let Some(path) = &self.path
+            && let Some(mut credential) = store.get(path)?
+        {
+            if let Some(ttl) = ttl {
+                credential.metadata.ttl = ttl;
+            }
+            credential.metadata.start_clock();
+            if credential.metadata.is_expired() {
+                store.delete(path)?;
+                return Ok(None);
+            }
+            let mut metadata = Metadata {
+                key_path: self.metadata.key_path.clone(),
+                key_type: self.metadata.key_type.clone(),
+                fingerprint: self.metadata.fingerprint.clone(),
+                ..credential.metadata
+            };
+            metadata.touch();
+            match store.store(&self.name, credential.secret.expose(), &metadata) {
+                Ok(()) => {
+                    store.delete(path)?;
+                }
+                Err(e) => eprintln!("Warning: Failed to migrate credential: {}", e),
+            }
+            return Ok(Some(Credential {
+                secret: credential.secret,
+                metadata,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    pub fn store(&self, store: &dyn CredentialStore, password: &str) -> Result<()> {
+        store.store(&self.name, password, &self.metadata.created_now())
+    }
+
+    pub fn delete(&self, store: &dyn CredentialStore) -> Result<()> {
+        store.delete(&self.name)?;
+        if let Some(path) = &self.path {
+            store.delete(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `ttl` setting for `entry`, which `ttl <key|pattern> = <duration>`
+/// lines in the config file override for single credentials. It applies to
+/// stored credentials too, in place of the ttl stored with them. None if
+/// nothing is configured, Some(None) for `never`.
+pub fn configured_ttl(entry: &CredentialEntry) -> Option<Option<u64>> {
+    let value = config::get_scoped("ttl", |scope| matches_pattern(entry, scope))?;
+    match config::parse_duration(&value) {
+        Ok(ttl) => Some(ttl),
+        Err(e) => {
+            eprintln!("Warning: Ignoring ttl setting: {}", e);
+            None
+        }
+    }
+}
+
+/// Applies the configured ttl to listed credentials.
+pub fn with_configured_ttl(mut entry: CredentialEntry) -> CredentialEntry {
+    if let Some(ttl) = configured_ttl(&entry) {
+        entry.metadata.ttl = ttl;
+    }
+    entry
+}
+
+/// Name of the program that asked for the password, e.g. "ssh-add".
+#[cfg(unix)]
+fn prompting_program() -> Option<String> {
+    let parent = std::os::unix::process::parent_id();
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", parent)).ok()?;
+    Some(comm.trim().to_string()).filter(|comm| !comm.is_empty())
+}
+
+#[cfg(not(unix))]
+fn prompting_program() -> Option<String> {
+    None
+}
+
+/// Whether `entry` is named by `pattern`, as given to `forget` or in a
+/// scoped setting.
+pub fn matches_pattern(entry: &CredentialEntry, pattern: &str) -> bool {
+    if pattern.contains(['*', '?']) {
+        return glob_matches(pattern, &entry.name)
+            || entry
+                .metadata
+                .key_path
+                .as_deref()
+                .is_some_and(|path| glob_matches(pattern, path));
+    }
+
+    let id = key::key_id(pattern);
+    entry.name == pattern
+        || entry.name == id.target
+        || entry.name == id.path
+        || entry.metadata.key_path.as_deref() == Some(id.path.as_str())
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of
+/// characters and `?` for a single character.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+pub mod memory {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    /// Credential store kept in memory, for tests.
+    #[derive(Default)]
+    pub struct MemoryStore {
+        entries: RefCell<BTreeMap<String, (String, Metadata)>>,
+    }
+
+    impl CredentialStore for MemoryStore {
+        fn name(&self) -> &'static str {
+            "memory"
+        }
+
+        fn get(&self, name: &str) -> Result<Option<Credential>> {
+            Ok(self
+                .entries
+                .borrow()
+                .get(name)
+                .map(|(secret, metadata)| Credential {
+                    secret: Secret::from(secret.clone()),
+                    metadata: metadata.clone(),
+                }))
+        }
+
+        fn store(&self, name: &str, secret: &str, metadata: &Metadata) -> Result<()> {
+            self.entries
+                .borrow_mut()
+                .insert(name.to_string(), (secret.to_string(), metadata.clone()));
+            Ok(())
+        }
+
+        fn delete(&self, name: &str) -> Result<bool> {
+            Ok(self.entries.borrow_mut().remove(name).is_some())
+        }
+
+        fn list(&self) -> Result<Vec<CredentialEntry>> {
+            Ok(self
+                .entries
+                .borrow()
+                .iter()
+                .map(|(name, (_, metadata))| CredentialEntry {
+                    name: name.clone(),
+                    metadata: metadata.clone(),
+                })
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::MemoryStore;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*", "/home/user/.ssh/id_rsa"));
+        assert!(glob_matches("/home/*/.ssh/id_*", "/home/user/.ssh/id_rsa"));
+        assert!(glob_matches("*id_rsa", "/home/user/.ssh/id_rsa"));
+        assert!(glob_matches("SHA256:???", "SHA256:abc"));
+        assert!(!glob_matches("*id_rsa", "/home/user/.ssh/id_rsa.old"));
+        assert!(!glob_matches("/home/*/id_rsa", "/root/.ssh/id_rsa"));
+    }
+
+    #[test]
+    fn test_path_credential_migrated_to_fingerprint() {
+        let store = MemoryStore::default();
+        store
+            .store("/home/user/.ssh/id_ed25519", "secret", &Metadata::default())
+            .unwrap();
+
+        let target = Target {
+            name: "SHA256:abc".to_string(),
+            path: Some("/home/user/.ssh/id_ed25519".to_string()),
+            metadata: Metadata {
+                key_path: Some("/home/user/.ssh/id_ed25519".to_string()),
+                fingerprint: Some("SHA256:abc".to_string()),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            target.get(&store).unwrap().as_ref().map(Secret::expose),
+            Some("secret")
+        );
+
+        let entries = store.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "SHA256:abc");
+        assert_eq!(
+            entries[0].metadata.key_path.as_deref(),
+            Some("/home/user/.ssh/id_ed25519")
+        );
+        assert_eq!(entries[0].metadata.use_hours, 1);
+    }
+
+    #[test]
+    fn test_entry_kind() {
+        let kind = |name: &str| {
+            CredentialEntry {
+                name: name.to_string(),
+                metadata: Metadata::default(),
+            }
+            .kind()
+        };
+        assert_eq!(kind("/home/user/.ssh/id_ed25519"), "ssh");
+        assert_eq!(kind("SHA256:abc"), "ssh");
+        assert_eq!(kind("git:https://github.com"), "git");
+        assert_eq!(kind("docker:https://index.docker.io/v1/"), "docker");
+        assert_eq!(kind("systemd:cryptsetup:/dev/sdb1"), "systemd");
+    }
+
+    #[test]
+    fn test_metadata_round_trips_through_attributes() {
+        let metadata = Metadata {
+            version: METADATA_VERSION,
+            created: Some(1_700_000_000),
+            last_recorded_use: Some(1_700_000_100),
+            use_hours: 3,
+            key_path: Some("/home/user/.ssh/id_ed25519".to_string()),
+            key_type: Some("ssh-ed25519".to_string()),
+            fingerprint: Some("SHA256:abc".to_string()),
+            program: Some("ssh-add".to_string()),
+            username: Some("git".to_string()),
+            ttl: Some(86400),
+        };
+
+        let parsed = Metadata::from_parts(metadata.key_path.clone(), metadata.attributes());
+        assert_eq!(parsed, metadata);
+    }
+
+    #[test]
+    fn test_comment_fits_credential_manager() {
+        let short = Metadata {
+            key_path: Some("/home/user/.ssh/id_ed25519".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(short.comment(), "/home/user/.ssh/id_ed25519");
+        assert_eq!(Metadata::default().comment(), "");
+
+        let long = Metadata {
+            key_path: Some(format!("/home/{}/\u{1F511}/id_ed25519", "a".repeat(249))),
+            ..Default::default()
+        };
+        // The key emoji takes two UTF-16 units and would end past the limit
+        assert_eq!(long.comment(), format!("/home/{}/", "a".repeat(249)));
+    }
+
+    #[test]
+    fn test_metadata_of_legacy_credential() {
+        let parsed = Metadata::from_parts(None, Vec::<(String, String)>::new());
+        assert_eq!(parsed.version, 0);
+        assert_eq!(parsed.created, None);
+        assert_eq!(parsed.use_hours, 0);
+    }
+
+    #[test]
+    fn test_touch_counts_hours_of_use() {
+        let mut metadata = Metadata::default().created_now();
+        metadata.touch();
+        metadata.touch();
+        assert_eq!(metadata.use_hours, 2);
+        assert!(metadata.last_recorded_use.is_some());
+    }
+
+    #[test]
+    fn test_lookup_rewrites_only_changes() {
+        let store = memory::MemoryStore::default();
+        let target = Target::named("git:https://example.com".to_string(), Metadata::default());
+        target.store(&store, "secret").unwrap();
+
+        // The first use is recorded, the next ones within the hour are not
+        for _ in 0..3 {
+            assert!(target.get(&store).unwrap().is_some());
+        }
+        let metadata = store.get(&target.name).unwrap().unwrap().metadata;
+        assert_eq!(metadata.use_hours, 1);
+
+        let mut stale = metadata.clone();
+        stale.last_recorded_use = Some(now() - USE_RECORD_INTERVAL);
+        store.store(&target.name, "secret", &stale).unwrap();
+        assert!(target.get(&store).unwrap().is_some());
+        let metadata = store.get(&target.name).unwrap().unwrap().metadata;
+        assert_eq!(metadata.use_hours, 2);
+        assert!(!metadata.use_is_stale());
+    }
+
+    #[test]
+    fn test_legacy_entry_expires_despite_use() {
+        let store = MemoryStore::default();
+        let target = Target::named("git:https://example.com".to_string(), Metadata::default());
+        // Stored before metadata had a creation time
+        let legacy = Metadata {
+            ttl: Some(100),
+            ..Default::default()
+        };
+        store.store(&target.name, "secret", &legacy).unwrap();
+
+        // The ttl starts at the first lookup and uses do not extend it
+        for _ in 0..3 {
+            assert!(target.get(&store).unwrap().is_some());
+        }
+        let mut metadata = store.get(&target.name).unwrap().unwrap().metadata;
+        let created = metadata.created.unwrap();
+        assert!(now() - created < 100);
+
+        metadata.created = Some(created - 100);
+        metadata.last_recorded_use = Some(now());
+        store.store(&target.name, "secret", &metadata).unwrap();
+        assert!(target.get(&store).unwrap().is_none());
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut metadata = Metadata {
+            created: Some(now() - 100),
+            ..Default::default()
+        };
+        assert!(!metadata.is_expired());
+
+        metadata.ttl = Some(1000);
+        assert!(!metadata.is_expired());
+
+        metadata.ttl = Some(100);
+        assert!(metadata.is_expired());
+
+        // Uses do not count: only the creation time does
+        metadata.created = None;
+        metadata.last_recorded_use = Some(now() - 100);
+        assert!(!metadata.is_expired());
+
+        metadata.ttl = Some(u64::MAX);
+        assert_eq!(metadata.expires(), None);
+    }
+}
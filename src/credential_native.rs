@@ -1,8 +1,12 @@
+use crate::credential::{Credential, CredentialEntry, CredentialStore, Metadata};
+use crate::error::Error;
+use crate::secret::{self, Secret};
 use anyhow::Result;
 use std::ptr;
+use windows::Win32::Foundation::ERROR_ACCESS_DENIED;
 use windows::Win32::Security::Credentials::{
-    CRED_FLAGS, CRED_PERSIST_LOCAL_MACHINE, CRED_TYPE_GENERIC, CREDENTIALW, CredEnumerateW,
-    CredFree, CredReadW, CredWriteW,
+    CRED_FLAGS, CRED_PERSIST_LOCAL_MACHINE, CRED_TYPE_GENERIC, CREDENTIAL_ATTRIBUTEW, CREDENTIALW,
+    CredDeleteW, CredEnumerateW, CredFree, CredReadW, CredWriteW,
 };
 use windows::core::PWSTR;
 
@@ -14,28 +18,97 @@ fn target_name(key_path: &str) -> String {
     format!("{}{}", CREDENTIAL_PREFIX, key_path)
 }
 
-pub fn get_credential(key_path: &str) -> Result<Option<String>> {
-    let target = target_name(key_path);
-    let target_wide: Vec<u16> = target.encode_utf16().chain(std::iter::once(0)).collect();
-
-    unsafe {
-        let mut credential_ptr: *mut CREDENTIALW = ptr::null_mut();
-        let result = CredReadW(
-            PWSTR(target_wide.as_ptr() as *mut u16),
-            CRED_TYPE_GENERIC,
-            Some(0),
-            &mut credential_ptr,
-        );
-
-        match result {
-            Ok(_) => {
-                if credential_ptr.is_null() {
-                    return Ok(None);
-                }
+/// Describes a failed call, typed as [`Error::AccessDenied`] when that is
+/// the cause.
+fn api_error(action: &str, e: windows::core::Error) -> anyhow::Error {
+    let message = format!("{}: {}", action, e);
+    if e.code() == ERROR_ACCESS_DENIED.to_hresult() {
+        Error::AccessDenied(message).into()
+    } else {
+        anyhow::anyhow!(message)
+    }
+}
+
+/// Reads the Comment and Attributes of a credential returned by the API.
+/// Attribute values are stored as UTF-8.
+unsafe fn read_metadata(credential: &CREDENTIALW) -> Result<Metadata> {
+    let comment = if credential.Comment.is_null() {
+        None
+    } else {
+        Some(unsafe { credential.Comment.to_string() }?)
+    };
+
+    let mut attributes = Vec::new();
+    if !credential.Attributes.is_null() {
+        let slice = unsafe {
+            std::slice::from_raw_parts(credential.Attributes, credential.AttributeCount as usize)
+        };
+        for attribute in slice {
+            if attribute.Keyword.is_null() {
+                continue;
+            }
+            let keyword = unsafe { attribute.Keyword.to_string() }?;
+            let value = if attribute.Value.is_null() {
+                String::new()
+            } else {
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(attribute.Value, attribute.ValueSize as usize)
+                };
+                String::from_utf8_lossy(bytes).into_owned()
+            };
+            attributes.push((keyword, value));
+        }
+    }
+
+    Ok(Metadata::from_parts(comment, attributes))
+}
+
+/// Reads an enumerated credential, None if it is not one of ours.
+unsafe fn read_entry(credential: &CREDENTIALW) -> Result<Option<CredentialEntry>> {
+    if credential.TargetName.is_null() {
+        return Ok(None);
+    }
+    let target_name = unsafe { credential.TargetName.to_string() }?;
+    let Some(key_path) = target_name.strip_prefix(CREDENTIAL_PREFIX) else {
+        return Ok(None);
+    };
+    Ok(Some(CredentialEntry {
+        name: key_path.to_string(),
+        metadata: unsafe { read_metadata(credential) }?,
+    }))
+}
+
+/// Credential Manager accessed through the Windows API.
+pub struct NativeStore;
+
+impl CredentialStore for NativeStore {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn get(&self, key_path: &str) -> Result<Option<Credential>> {
+        let target = target_name(key_path);
+        let target_wide: Vec<u16> = target.encode_utf16().chain(std::iter::once(0)).collect();
 
-                let credential = &*credential_ptr;
-                let password =
-                    if credential.CredentialBlob.is_null() || credential.CredentialBlobSize == 0 {
+        unsafe {
+            let mut credential_ptr: *mut CREDENTIALW = ptr::null_mut();
+            let result = CredReadW(
+                PWSTR(target_wide.as_ptr() as *mut u16),
+                CRED_TYPE_GENERIC,
+                Some(0),
+                &mut credential_ptr,
+            );
+
+            match result {
+                Ok(_) => {
+                    if credential_ptr.is_null() {
+                        return Ok(None);
+                    }
+
+                    let credential = &*credential_ptr;
+                    let password = if credential.CredentialBlob.is_null()
+                        || credential.CredentialBlobSize == 0
+                    {
                         None
                     } else {
                         // Password is stored as Unicode (UTF-16)
@@ -43,99 +116,148 @@ pub fn get_credential(key_path: &str) -> Result<Option<String>> {
                             credential.CredentialBlob as *const u16,
                             credential.CredentialBlobSize as usize / 2,
                         );
-                        Some(String::from_utf16_lossy(slice))
+                        Some(Secret::from_utf16(slice))
                     };
+                    if !credential.CredentialBlob.is_null() {
+                        secret::wipe(std::slice::from_raw_parts_mut(
+                            credential.CredentialBlob,
+                            credential.CredentialBlobSize as usize,
+                        ));
+                    }
+                    let metadata = read_metadata(credential);
 
-                CredFree(credential_ptr as *const _);
-                Ok(password)
-            }
-            Err(e) => {
-                if e.code().0 as u32 == ERROR_NOT_FOUND {
-                    Ok(None)
-                } else {
-                    Err(anyhow::anyhow!("Failed to read credential: {}", e))
+                    CredFree(credential_ptr as *const _);
+                    let metadata = metadata?;
+                    Ok(password.map(|secret| Credential { secret, metadata }))
+                }
+                Err(e) => {
+                    if e.code().0 as u32 == ERROR_NOT_FOUND {
+                        Ok(None)
+                    } else {
+                        Err(api_error("Failed to read credential", e))
+                    }
                 }
             }
         }
     }
-}
 
-pub fn store_credential(key_path: &str, passphrase: &str) -> Result<()> {
-    let target = target_name(key_path);
-    let target_wide: Vec<u16> = target.encode_utf16().chain(std::iter::once(0)).collect();
-    let username_wide: Vec<u16> = vec![0]; // Empty username
-    let password_bytes: Vec<u16> = passphrase.encode_utf16().collect();
-
-    unsafe {
-        let mut credential = CREDENTIALW {
-            Flags: CRED_FLAGS(0),
-            Type: CRED_TYPE_GENERIC,
-            TargetName: PWSTR(target_wide.as_ptr() as *mut u16),
-            Comment: PWSTR::null(),
-            LastWritten: Default::default(),
-            CredentialBlobSize: (password_bytes.len() * 2) as u32,
-            CredentialBlob: password_bytes.as_ptr() as *mut u8,
-            Persist: CRED_PERSIST_LOCAL_MACHINE,
-            AttributeCount: 0,
-            Attributes: ptr::null_mut(),
-            TargetAlias: PWSTR::null(),
-            UserName: PWSTR(username_wide.as_ptr() as *mut u16),
-        };
+    fn store(&self, key_path: &str, passphrase: &str, metadata: &Metadata) -> Result<()> {
+        let target = target_name(key_path);
+        let target_wide: Vec<u16> = target.encode_utf16().chain(std::iter::once(0)).collect();
+        let username_wide: Vec<u16> = vec![0]; // Empty username
+        let comment_wide: Vec<u16> = metadata
+            .comment()
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+        let password_bytes = secret::utf16(passphrase);
+
+        // The attribute structs point into these buffers, which must outlive CredWriteW
+        let attribute_data: Vec<(Vec<u16>, Vec<u8>)> = metadata
+            .attributes()
+            .into_iter()
+            .map(|(keyword, value)| {
+                (
+                    keyword.encode_utf16().chain(std::iter::once(0)).collect(),
+                    value.into_bytes(),
+                )
+            })
+            .collect();
+        let mut attributes: Vec<CREDENTIAL_ATTRIBUTEW> = attribute_data
+            .iter()
+            .map(|(keyword, value)| CREDENTIAL_ATTRIBUTEW {
+                Keyword: PWSTR(keyword.as_ptr() as *mut u16),
+                Flags: 0,
+                ValueSize: value.len() as u32,
+                Value: value.as_ptr() as *mut u8,
+            })
+            .collect();
+
+        unsafe {
+            let mut credential = CREDENTIALW {
+                Flags: CRED_FLAGS(0),
+                Type: CRED_TYPE_GENERIC,
+                TargetName: PWSTR(target_wide.as_ptr() as *mut u16),
+                Comment: PWSTR(comment_wide.as_ptr() as *mut u16),
+                LastWritten: Default::default(),
+                CredentialBlobSize: (password_bytes.len() * 2) as u32,
+                CredentialBlob: password_bytes.as_ptr() as *mut u8,
+                Persist: CRED_PERSIST_LOCAL_MACHINE,
+                AttributeCount: attributes.len() as u32,
+                Attributes: attributes.as_mut_ptr(),
+                TargetAlias: PWSTR::null(),
+                UserName: PWSTR(username_wide.as_ptr() as *mut u16),
+            };
 
-        CredWriteW(&mut credential, 0)?;
+            CredWriteW(&mut credential, 0)
+                .map_err(|e| api_error("Failed to save credential", e))?;
+        }
+
+        Ok(())
     }
 
-    Ok(())
-}
+    fn delete(&self, key_path: &str) -> Result<bool> {
+        let target = target_name(key_path);
+        let target_wide: Vec<u16> = target.encode_utf16().chain(std::iter::once(0)).collect();
+
+        unsafe {
+            match CredDeleteW(
+                PWSTR(target_wide.as_ptr() as *mut u16),
+                CRED_TYPE_GENERIC,
+                Some(0),
+            ) {
+                Ok(_) => Ok(true),
+                Err(e) => {
+                    if e.code().0 as u32 == ERROR_NOT_FOUND {
+                        Ok(false)
+                    } else {
+                        Err(api_error("Failed to delete credential", e))
+                    }
+                }
+            }
+        }
+    }
+
+    fn list(&self) -> Result<Vec<CredentialEntry>> {
+        let filter: Vec<u16> = format!("{}*", CREDENTIAL_PREFIX)
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+
+        unsafe {
+            let mut count: u32 = 0;
+            let mut credentials_ptr: *mut *mut CREDENTIALW = ptr::null_mut();
 
-pub fn list_credentials() -> Result<Vec<String>> {
-    let filter: Vec<u16> = format!("{}*", CREDENTIAL_PREFIX)
-        .encode_utf16()
-        .chain(std::iter::once(0))
-        .collect();
-
-    unsafe {
-        let mut count: u32 = 0;
-        let mut credentials_ptr: *mut *mut CREDENTIALW = ptr::null_mut();
-
-        let result = CredEnumerateW(
-            PWSTR(filter.as_ptr() as *mut u16),
-            None,
-            &mut count,
-            &mut credentials_ptr,
-        );
-
-        match result {
-            Ok(_) => {
-                let mut results = Vec::new();
-
-                if !credentials_ptr.is_null() {
-                    let credentials_slice =
-                        std::slice::from_raw_parts(credentials_ptr, count as usize);
-
-                    for &cred_ptr in credentials_slice {
-                        if !cred_ptr.is_null() {
-                            let cred = &*cred_ptr;
-                            if !cred.TargetName.is_null() {
-                                let target_name = cred.TargetName.to_string()?;
-                                if let Some(key_path) = target_name.strip_prefix(CREDENTIAL_PREFIX)
-                                {
-                                    results.push(key_path.to_string());
-                                }
-                            }
-                        }
+            let result = CredEnumerateW(
+                PWSTR(filter.as_ptr() as *mut u16),
+                None,
+                &mut count,
+                &mut credentials_ptr,
+            );
+
+            match result {
+                Ok(_) => {
+                    if credentials_ptr.is_null() {
+                        return Ok(Vec::new());
                     }
 
+                    // Everything is copied out before the array is freed, so
+                    // a credential that fails to read cannot leak it
+                    let results = std::slice::from_raw_parts(credentials_ptr, count as usize)
+                        .iter()
+                        .filter(|cred_ptr| !cred_ptr.is_null())
+                        .filter_map(|&cred_ptr| read_entry(&*cred_ptr).transpose())
+                        .collect::<Result<Vec<_>>>();
+
                     CredFree(credentials_ptr as *const _);
+                    results
                 }
-
-                Ok(results)
-            }
-            Err(e) => {
-                if e.code().0 as u32 == ERROR_NOT_FOUND {
-                    Ok(Vec::new())
-                } else {
-                    Err(anyhow::anyhow!("Failed to enumerate credentials: {}", e))
+                Err(e) => {
+                    if e.code().0 as u32 == ERROR_NOT_FOUND {
+                        Ok(Vec::new())
+                    } else {
+                        Err(api_error("Failed to enumerate credentials", e))
+                    }
                 }
             }
         }
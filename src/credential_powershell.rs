@@ -1,53 +1,105 @@
-use anyhow::{Context, Result};
-use std::process::Command;
+use crate::credential::{Credential, CredentialEntry, CredentialStore, Metadata};
+use crate::error::Error;
+use crate::powershell;
+use crate::secret::Secret;
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
 
 const CREDENTIAL_PREFIX: &str = "winaskpass:";
 
-fn powershell(script: &str) -> Result<String> {
-    let output = Command::new("powershell.exe")
-        .args(["-NoProfile", "-NonInteractive", "-Command", script])
-        .output()
-        .context("Failed to execute PowerShell")?;
+/// Credential Manager accessed through `powershell.exe` and embedded C#.
+pub struct PowerShellStore {
+    program: PathBuf,
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("PowerShell error: {}", stderr);
+impl Default for PowerShellStore {
+    fn default() -> Self {
+        PowerShellStore {
+            program: PathBuf::from("powershell.exe"),
+        }
     }
+}
 
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+/// Input of [`STORE_SCRIPT`].
+#[derive(Serialize)]
+struct StoreInput<'a> {
+    target: String,
+    secret: &'a str,
+    comment: &'a str,
+    attributes: Vec<String>,
+}
+
+impl PowerShellStore {
+    fn powershell(&self, script: &str, input: &impl Serialize) -> Result<Secret> {
+        powershell::execute(
+            &self.program,
+            &["-NoProfile", "-NonInteractive"],
+            script,
+            input,
+        )
+    }
 }
 
-fn target_name(key_path: &str) -> String {
+pub(crate) fn target_name(key_path: &str) -> String {
     format!("{}{}", CREDENTIAL_PREFIX, key_path)
 }
 
-pub fn get_credential(key_path: &str) -> Result<Option<String>> {
-    let target = target_name(key_path);
-    let script = format!(
+/// Defines `CredentialManager`, reading credentials, and `CredentialLister`,
+/// listing them, both as `StoredCredential`s; attribute values are stored
+/// as UTF-8. The types are added in one block, the same in every script,
+/// since PowerShell refuses to add a type again from a different source.
+/// `ConvertTo-Entry` turns a `StoredCredential` into what [`StoredEntry`]
+/// reads, with the secret base64 encoded.
+macro_rules! reader_type {
+    () => {
         r#"
-        $cred = Get-StoredCredential -Target '{target}' -ErrorAction SilentlyContinue
-        if ($cred) {{
-            $cred.GetNetworkCredential().Password
-        }}
-        "#,
-        target = target.replace("'", "''")
-    );
-
-    // Try with CredentialManager module first
-    match powershell(&script) {
-        Ok(password) if !password.is_empty() => return Ok(Some(password)),
-        _ => {}
-    }
-
-    // Fallback: use cmdkey and direct API via .NET
-    let script = format!(
-        r#"
-        Add-Type -TypeDefinition @"
+Add-Type -TypeDefinition @"
 using System;
+using System.Collections.Generic;
 using System.Runtime.InteropServices;
 using System.Text;
 
-public class CredentialManager {{
+public class StoredCredential {
+    public string Name;
+    public string Comment;
+    // Null when listing
+    public string Secret;
+    // Keywords and values alternate
+    public string[] Attributes;
+}
+
+public class CredentialAttributes {
+    [StructLayout(LayoutKind.Sequential, CharSet = CharSet.Unicode)]
+    public struct CREDENTIAL_ATTRIBUTE {
+        public string Keyword;
+        public int Flags;
+        public int ValueSize;
+        public IntPtr Value;
+    }
+
+    // Keywords and values alternate
+    public static string[] Read(IntPtr attributes, int count) {
+        var results = new string[2 * count];
+        int size = Marshal.SizeOf(typeof(CREDENTIAL_ATTRIBUTE));
+        for (int i = 0; i < count; i++) {
+            var attribute = (CREDENTIAL_ATTRIBUTE)Marshal.PtrToStructure(
+                new IntPtr(attributes.ToInt64() + i * size), typeof(CREDENTIAL_ATTRIBUTE));
+            byte[] value = new byte[attribute.ValueSize];
+            if (attribute.ValueSize > 0) {
+                Marshal.Copy(attribute.Value, value, 0, attribute.ValueSize);
+            }
+            results[2 * i] = attribute.Keyword;
+            results[2 * i + 1] = Encoding.UTF8.GetString(value);
+        }
+        return results;
+    }
+}
+
+public class CredentialManager {
     [DllImport("advapi32.dll", SetLastError = true, CharSet = CharSet.Unicode)]
     public static extern bool CredReadW(string target, int type, int flags, out IntPtr credential);
 
@@ -55,7 +107,7 @@ public class CredentialManager {{
     public static extern void CredFree(IntPtr credential);
 
     [StructLayout(LayoutKind.Sequential, CharSet = CharSet.Unicode)]
-    public struct CREDENTIAL {{
+    public struct CREDENTIAL {
         public int Flags;
         public int Type;
         public string TargetName;
@@ -68,55 +120,136 @@ public class CredentialManager {{
         public IntPtr Attributes;
         public string TargetAlias;
         public string UserName;
-    }}
+    }
 
-    public static string GetPassword(string target) {{
+    public const int ERROR_NOT_FOUND = 1168;
+
+    // Null if there is no such credential or it holds no password
+    public static StoredCredential Read(string target) {
         IntPtr credPtr;
-        if (CredReadW(target, 1, 0, out credPtr)) {{
-            try {{
+        if (!CredReadW(target, 1, 0, out credPtr)) {
+            int error = Marshal.GetLastWin32Error();
+            if (error == ERROR_NOT_FOUND) {
+                return null;
+            }
+            throw new System.ComponentModel.Win32Exception(error, "CredReadW failed: " + error);
+        }
+        try {
+            var cred = (CREDENTIAL)Marshal.PtrToStructure(credPtr, typeof(CREDENTIAL));
+            if (cred.CredentialBlob == IntPtr.Zero || cred.CredentialBlobSize == 0) {
+                return null;
+            }
+            var result = new StoredCredential();
+            result.Name = cred.TargetName;
+            result.Comment = cred.Comment;
+            result.Secret = Marshal.PtrToStringUni(cred.CredentialBlob, cred.CredentialBlobSize / 2);
+            result.Attributes = CredentialAttributes.Read(cred.Attributes, cred.AttributeCount);
+            return result;
+        } finally {
+            CredFree(credPtr);
+        }
+    }
+}
+
+public class CredentialLister {
+    [DllImport("advapi32.dll", SetLastError = true, CharSet = CharSet.Unicode)]
+    public static extern bool CredEnumerateW(string filter, int flags, out int count, out IntPtr credentials);
+
+    [DllImport("advapi32.dll")]
+    public static extern void CredFree(IntPtr credential);
+
+    public const int ERROR_NOT_FOUND = 1168;
+
+    [StructLayout(LayoutKind.Sequential, CharSet = CharSet.Unicode)]
+    public struct CREDENTIAL {
+        public int Flags;
+        public int Type;
+        public string TargetName;
+        public string Comment;
+        public long LastWritten;
+        public int CredentialBlobSize;
+        public IntPtr CredentialBlob;
+        public int Persist;
+        public int AttributeCount;
+        public IntPtr Attributes;
+        public string TargetAlias;
+        public string UserName;
+    }
+
+    public static StoredCredential[] List(string prefix) {
+        var results = new List<StoredCredential>();
+        int count;
+        IntPtr credentials;
+
+        if (!CredEnumerateW(prefix + "*", 0, out count, out credentials)) {
+            int error = Marshal.GetLastWin32Error();
+            if (error == ERROR_NOT_FOUND) {
+                return results.ToArray();
+            }
+            throw new System.ComponentModel.Win32Exception(error, "CredEnumerateW failed: " + error);
+        }
+        try {
+            for (int i = 0; i < count; i++) {
+                IntPtr credPtr = Marshal.ReadIntPtr(credentials, i * IntPtr.Size);
                 var cred = (CREDENTIAL)Marshal.PtrToStructure(credPtr, typeof(CREDENTIAL));
-                if (cred.CredentialBlob != IntPtr.Zero && cred.CredentialBlobSize > 0) {{
-                    return Marshal.PtrToStringUni(cred.CredentialBlob, cred.CredentialBlobSize / 2);
-                }}
-            }} finally {{
-                CredFree(credPtr);
-            }}
-        }}
-        return null;
-    }}
-}}
+                if (cred.TargetName != null && cred.TargetName.StartsWith(prefix)) {
+                    var result = new StoredCredential();
+                    result.Name = cred.TargetName;
+                    result.Comment = cred.Comment;
+                    result.Attributes = CredentialAttributes.Read(cred.Attributes, cred.AttributeCount);
+                    results.Add(result);
+                }
+            }
+        } finally {
+            CredFree(credentials);
+        }
+        return results.ToArray();
+    }
+}
 "@
 
-$result = [CredentialManager]::GetPassword('{target}')
-if ($result) {{ $result }}
-"#,
-        target = target.replace("'", "''")
-    );
-
-    let password = powershell(&script)?;
-    if password.is_empty() {
-        Ok(None)
-    } else {
-        Ok(Some(password))
+function ConvertTo-Entry($credential) {
+    $attributes = [ordered]@{}
+    for ($i = 0; $i + 1 -lt $credential.Attributes.Length; $i += 2) {
+        $attributes[$credential.Attributes[$i]] = $credential.Attributes[$i + 1]
+    }
+    $entry = [ordered]@{
+        name = $credential.Name
+        comment = $credential.Comment
+        attributes = $attributes
+    }
+    if ($credential.Secret -ne $null) {
+        $entry.secret = [Convert]::ToBase64String([System.Text.Encoding]::UTF8.GetBytes($credential.Secret))
     }
+    $entry
 }
+"#
+    };
+}
+
+const GET_SCRIPT: &str = concat!(
+    reader_type!(),
+    r#"
+$credential = [CredentialManager]::Read($data.target)
+if ($credential) { ConvertTo-Entry $credential | ConvertTo-Json -Compress -Depth 3 }
+"#
+);
 
-pub fn store_credential(key_path: &str, passphrase: &str) -> Result<()> {
-    let target = target_name(key_path);
-    let username = "";
-    let script = format!(
+/// Defines `CredentialWriter`, writing credentials.
+macro_rules! writer_type {
+    () => {
         r#"
-        Add-Type -TypeDefinition @"
+Add-Type -TypeDefinition @"
 using System;
 using System.Runtime.InteropServices;
 using System.Text;
 
-public class CredentialWriter {{
+public class CredentialWriter {
     [DllImport("advapi32.dll", SetLastError = true, CharSet = CharSet.Unicode)]
     public static extern bool CredWriteW(ref CREDENTIAL credential, int flags);
 
     [StructLayout(LayoutKind.Sequential, CharSet = CharSet.Unicode)]
-    public struct CREDENTIAL {{
+    public struct CREDENTIAL {
         public int Flags;
         public int Type;
         public string TargetName;
@@ -129,105 +262,444 @@ public class CredentialWriter {{
         public IntPtr Attributes;
         public string TargetAlias;
         public string UserName;
-    }}
+    }
 
-    public static void Write(string target, string password, string username) {{
+    [StructLayout(LayoutKind.Sequential)]
+    public struct CREDENTIAL_ATTRIBUTE {
+        public IntPtr Keyword;
+        public int Flags;
+        public int ValueSize;
+        public IntPtr Value;
+    }
+
+    // attributes alternates keywords and values
+    public static void Write(string target, string password, string username, string comment, string[] attributes) {
         byte[] byteArray = Encoding.Unicode.GetBytes(password);
         CREDENTIAL cred = new CREDENTIAL();
         cred.Type = 1; // CRED_TYPE_GENERIC
         cred.TargetName = target;
+        if (comment.Length > 0) {
+            cred.Comment = comment;
+        }
         cred.CredentialBlobSize = byteArray.Length;
         cred.CredentialBlob = Marshal.AllocHGlobal(byteArray.Length);
         Marshal.Copy(byteArray, 0, cred.CredentialBlob, byteArray.Length);
         cred.Persist = 2; // CRED_PERSIST_LOCAL_MACHINE
         cred.UserName = username;
 
-        try {{
-            if (!CredWriteW(ref cred, 0)) {{
-                throw new Exception("CredWriteW failed: " + Marshal.GetLastWin32Error());
-            }}
-        }} finally {{
-            Marshal.FreeHGlobal(cred.CredentialBlob);
-        }}
-    }}
-}}
-"@
+        int count = attributes.Length / 2;
+        int size = Marshal.SizeOf(typeof(CREDENTIAL_ATTRIBUTE));
+        var allocated = new System.Collections.Generic.List<IntPtr>();
+        cred.AttributeCount = count;
+        cred.Attributes = Marshal.AllocHGlobal(size * count);
 
-[CredentialWriter]::Write('{target}', '{passphrase}', '{username}')
-"#,
-        target = target.replace("'", "''"),
-        passphrase = passphrase.replace("'", "''"),
-        username = username.replace("'", "''")
-    );
+        try {
+            for (int i = 0; i < count; i++) {
+                byte[] value = Encoding.UTF8.GetBytes(attributes[2 * i + 1]);
+                CREDENTIAL_ATTRIBUTE attribute = new CREDENTIAL_ATTRIBUTE();
+                attribute.Keyword = Marshal.StringToHGlobalUni(attributes[2 * i]);
+                allocated.Add(attribute.Keyword);
+                attribute.ValueSize = value.Length;
+                attribute.Value = Marshal.AllocHGlobal(Math.Max(value.Length, 1));
+                allocated.Add(attribute.Value);
+                Marshal.Copy(value, 0, attribute.Value, value.Length);
+                Marshal.StructureToPtr(attribute, new IntPtr(cred.Attributes.ToInt64() + i * size), false);
+            }
 
-    powershell(&script)?;
-    Ok(())
+            if (!CredWriteW(ref cred, 0)) {
+                int error = Marshal.GetLastWin32Error();
+                throw new System.ComponentModel.Win32Exception(error, "CredWriteW failed: " + error);
+            }
+        } finally {
+            Marshal.FreeHGlobal(cred.CredentialBlob);
+            foreach (IntPtr pointer in allocated) {
+                Marshal.FreeHGlobal(pointer);
+            }
+            Marshal.FreeHGlobal(cred.Attributes);
+        }
+    }
+}
+"@
+"#
+    };
 }
 
-pub fn list_credentials() -> Result<Vec<String>> {
-    let script = format!(
+const STORE_SCRIPT: &str = concat!(
+    writer_type!(),
+    r#"
+[CredentialWriter]::Write($data.target, $data.secret, '', $data.comment, [string[]]$data.attributes)
+"#
+);
+
+/// Defines `CredentialDeleter`, deleting credentials.
+macro_rules! deleter_type {
+    () => {
         r#"
-        Add-Type -TypeDefinition @"
+Add-Type -TypeDefinition @"
 using System;
-using System.Collections.Generic;
 using System.Runtime.InteropServices;
 
-public class CredentialLister {{
+public class CredentialDeleter {
     [DllImport("advapi32.dll", SetLastError = true, CharSet = CharSet.Unicode)]
-    public static extern bool CredEnumerateW(string filter, int flags, out int count, out IntPtr credentials);
+    public static extern bool CredDeleteW(string target, int type, int flags);
 
-    [DllImport("advapi32.dll")]
-    public static extern void CredFree(IntPtr credential);
+    public const int ERROR_NOT_FOUND = 1168;
 
-    [StructLayout(LayoutKind.Sequential, CharSet = CharSet.Unicode)]
-    public struct CREDENTIAL {{
-        public int Flags;
-        public int Type;
-        public string TargetName;
-        public string Comment;
-        public long LastWritten;
-        public int CredentialBlobSize;
-        public IntPtr CredentialBlob;
-        public int Persist;
-        public int AttributeCount;
-        public IntPtr Attributes;
-        public string TargetAlias;
-        public string UserName;
-    }}
+    public static bool Delete(string target) {
+        if (CredDeleteW(target, 1, 0)) {
+            return true;
+        }
+        int error = Marshal.GetLastWin32Error();
+        if (error == ERROR_NOT_FOUND) {
+            return false;
+        }
+        throw new System.ComponentModel.Win32Exception(error, "CredDeleteW failed: " + error);
+    }
+}
+"@
+"#
+    };
+}
 
-    public static string[] List(string prefix) {{
-        var results = new List<string>();
-        int count;
-        IntPtr credentials;
+const DELETE_SCRIPT: &str = concat!(
+    deleter_type!(),
+    r#"
+if ([CredentialDeleter]::Delete($data.target)) { 'deleted' }
+"#
+);
 
-        if (CredEnumerateW(prefix + "*", 0, out count, out credentials)) {{
-            try {{
-                for (int i = 0; i < count; i++) {{
-                    IntPtr credPtr = Marshal.ReadIntPtr(credentials, i * IntPtr.Size);
-                    var cred = (CREDENTIAL)Marshal.PtrToStructure(credPtr, typeof(CREDENTIAL));
-                    if (cred.TargetName != null && cred.TargetName.StartsWith(prefix)) {{
-                        results.Add(cred.TargetName.Substring(prefix.Length));
-                    }}
-                }}
-            }} finally {{
-                CredFree(credentials);
-            }}
-        }}
-        return results.ToArray();
-    }}
-}}
-"@
+pub(crate) use {deleter_type, reader_type, writer_type};
+
+const LIST_SCRIPT: &str = concat!(
+    reader_type!(),
+    r#"
+$entries = foreach ($credential in [CredentialLister]::List($data.prefix)) {
+    ConvertTo-Entry $credential
+}
+ConvertTo-Json -InputObject @($entries) -Compress -Depth 3
+"#
+);
+
+/// A credential as `ConvertTo-Entry` describes it.
+#[derive(Deserialize)]
+pub(crate) struct StoredEntry {
+    /// Target name, with the prefix
+    name: String,
+    comment: Option<String>,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+    /// Base64 encoded UTF-8, left out when listing
+    secret: Option<Secret>,
+}
+
+impl StoredEntry {
+    /// The name without the prefix, None for a credential that is not ours.
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.strip_prefix(CREDENTIAL_PREFIX)
+    }
+
+    fn metadata(self) -> Metadata {
+        Metadata::from_parts(self.comment, self.attributes)
+    }
+
+    /// The credential read, with its secret decoded.
+    pub(crate) fn into_credential(self) -> Result<Credential> {
+        let bytes = STANDARD
+            .decode(self.secret.as_ref().map_or("", Secret::expose))
+            .map_err(|_| malformed("Invalid secret in credential output"))?;
+        Ok(Credential {
+            secret: Secret::from_utf8(bytes),
+            metadata: self.metadata(),
+        })
+    }
+}
+
+/// The output is not quoted in errors: it may hold the secret.
+pub(crate) fn malformed(message: &str) -> anyhow::Error {
+    Error::MalformedOutput(message.to_string()).into()
+}
+
+/// Parses the output of the read script, which is empty when there is no
+/// such credential.
+fn parse_credential(output: &str) -> Result<Option<Credential>> {
+    if output.trim().is_empty() {
+        return Ok(None);
+    }
+    let entry: StoredEntry =
+        serde_json::from_str(output).map_err(|_| malformed("Unexpected credential output"))?;
+    entry.into_credential().map(Some)
+}
+
+/// Parses the output of the list script.
+fn parse_entries(output: &str) -> Result<Vec<CredentialEntry>> {
+    let entries: Vec<StoredEntry> =
+        serde_json::from_str(output).map_err(|_| malformed("Unexpected credential list"))?;
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| {
+            let name = entry.name()?.to_string();
+            Some(CredentialEntry {
+                name,
+                metadata: entry.metadata(),
+            })
+        })
+        .collect())
+}
+
+impl CredentialStore for PowerShellStore {
+    fn name(&self) -> &'static str {
+        "powershell"
+    }
+
+    fn get(&self, key_path: &str) -> Result<Option<Credential>> {
+        let input = serde_json::json!({ "target": target_name(key_path) });
+
+        parse_credential(self.powershell(GET_SCRIPT, &input)?.expose())
+    }
+
+    fn store(&self, key_path: &str, passphrase: &str, metadata: &Metadata) -> Result<()> {
+        // Borrowed rather than built with json!, which would copy the
+        // passphrase into a string that is not wiped
+        let input = StoreInput {
+            target: target_name(key_path),
+            secret: passphrase,
+            comment: metadata.comment(),
+            attributes: metadata
+                .attributes()
+                .into_iter()
+                .flat_map(|(keyword, value)| [keyword.to_string(), value])
+                .collect(),
+        };
+
+        self.powershell(STORE_SCRIPT, &input)?;
+        Ok(())
+    }
+
+    fn delete(&self, key_path: &str) -> Result<bool> {
+        let input = serde_json::json!({ "target": target_name(key_path) });
+
+        Ok(self.powershell(DELETE_SCRIPT, &input)?.expose().trim() == "deleted")
+    }
+
+    fn list(&self) -> Result<Vec<CredentialEntry>> {
+        let input = serde_json::json!({ "prefix": CREDENTIAL_PREFIX });
+
+        parse_entries(self.powershell(LIST_SCRIPT, &input)?.expose())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_scripts_are_well_formed() {
+        for script in [GET_SCRIPT, STORE_SCRIPT, DELETE_SCRIPT, LIST_SCRIPT] {
+            assert!(powershell::parse(script).errors.is_empty());
+        }
+    }
+
+    /// The runner keeps types between scripts, and adding one again from a
+    /// different source fails.
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_types_defined_alike_everywhere() {
+        let mut sources = HashMap::new();
+        for script in [
+            GET_SCRIPT,
+            STORE_SCRIPT,
+            DELETE_SCRIPT,
+            LIST_SCRIPT,
+            crate::askpass_powershell::ASKPASS_SCRIPT,
+            crate::dialog_powershell::PASSWORD_SCRIPT,
+        ] {
+            for added in powershell::parse(script).types {
+                let source = sources
+                    .entry(added.name.clone())
+                    .or_insert(added.source.clone());
+                assert_eq!(*source, added.source, "{}", added.name);
+            }
+        }
+        assert!(sources.contains_key("CredentialAttributes"));
+        assert!(sources.contains_key("CredentialLister"));
+    }
+
+    /// The read script's output for `secret` and the metadata given.
+    fn read_output(comment: &str, attributes: serde_json::Value, secret: &str) -> String {
+        serde_json::json!({
+            "name": "winaskpass:SHA256:abc",
+            "comment": comment,
+            "attributes": attributes,
+            "secret": STANDARD.encode(secret),
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_parse_credential() {
+        let output = read_output(
+            "/home/user/.ssh/id_rsa",
+            serde_json::json!({ "version": "1", "use_hours": "4" }),
+            "pass\nword",
+        );
+        let credential = parse_credential(&output).unwrap().unwrap();
+        assert_eq!(credential.secret.expose(), "pass\nword");
+        assert_eq!(
+            credential.metadata.key_path.as_deref(),
+            Some("/home/user/.ssh/id_rsa")
+        );
+        assert_eq!(credential.metadata.version, 1);
+        assert_eq!(credential.metadata.use_hours, 4);
+        assert!(parse_credential("").unwrap().is_none());
+        assert!(parse_credential("comment:\npassword:\nsecret").is_err());
+    }
+
+    #[test]
+    fn test_separators_in_fields_kept() {
+        // A field cannot end early or pass for another
+        let output = read_output(
+            "/home/me/odd\npassword:\tdir/id_rsa",
+            serde_json::json!({ "username": "user\tattr:ttl=1\nx", "ttl": "60" }),
+            "secret",
+        );
+        let credential = parse_credential(&output).unwrap().unwrap();
+        assert_eq!(credential.secret.expose(), "secret");
+        assert_eq!(
+            credential.metadata.key_path.as_deref(),
+            Some("/home/me/odd\npassword:\tdir/id_rsa")
+        );
+        assert_eq!(
+            credential.metadata.username.as_deref(),
+            Some("user\tattr:ttl=1\nx")
+        );
+        assert_eq!(credential.metadata.ttl, Some(60));
+
+        let output = serde_json::json!([{
+            "name": "winaskpass:git:https://example.com/a\tb\nc",
+            "comment": null,
+            "attributes": { "username": "x\ty" },
+        }]);
+        let entries = parse_entries(&output.to_string()).unwrap();
+        assert_eq!(entries[0].name, "git:https://example.com/a\tb\nc");
+        assert_eq!(entries[0].metadata.username.as_deref(), Some("x\ty"));
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        let output = r#"[
+            {"name":"winaskpass:SHA256:abc","comment":"/home/user/.ssh/id_ed25519",
+             "attributes":{"key_type":"ssh-ed25519"}},
+            {"name":"winaskpass:/home/user/.ssh/id_rsa","comment":null,"attributes":{}},
+            {"name":"other:thing","comment":null,"attributes":{}}
+        ]"#;
+        let entries = parse_entries(output).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "SHA256:abc");
+        assert_eq!(entries[0].metadata.key_type.as_deref(), Some("ssh-ed25519"));
+        assert_eq!(entries[1].metadata.key_path, None);
+        assert_eq!(entries[1].metadata.version, 0);
+        assert!(parse_entries("[]").unwrap().is_empty());
+    }
+
+    /// `ConvertTo-Entry` writes what the parser reads, separators and all.
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_entry_written_as_parsed() {
+        let script = concat!(
+            reader_type!(),
+            r#"
+$credential = New-Object StoredCredential
+$credential.Name = $data.name
+$credential.Comment = $data.comment
+$credential.Secret = $data.secret
+$credential.Attributes = [string[]]$data.attributes
+ConvertTo-Entry $credential | ConvertTo-Json -Compress -Depth 3
+"#
+        );
+        let input = serde_json::json!({
+            "name": "winaskpass:SHA256:abc",
+            "comment": "/home/me/a\nb\tc",
+            "secret": "pässwörd\n\t🔑",
+            "attributes": ["username", "x\ny\tz", "use_hours", "2"],
+        });
+        let program = powershell::installed().expect("PowerShell is not installed");
+        let output = powershell::execute(&program, &["-NoProfile"], script, &input).unwrap();
+
+        let credential = parse_credential(output.expose()).unwrap().unwrap();
+        assert_eq!(credential.secret.expose(), "pässwörd\n\t🔑");
+        assert_eq!(
+            credential.metadata.key_path.as_deref(),
+            Some("/home/me/a\nb\tc")
+        );
+        assert_eq!(credential.metadata.username.as_deref(), Some("x\ny\tz"));
+        assert_eq!(credential.metadata.use_hours, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_sent_on_stdin_not_argv() {
+        let stand_in = powershell::stand_in::StandIn::new("store", "");
+        let store = PowerShellStore {
+            program: stand_in.program.clone(),
+        };
+
+        let metadata = Metadata {
+            key_path: Some("/home/user/.ssh/id_ed25519".to_string()),
+            ..Default::default()
+        };
+        store
+            .store(
+                "/home/user/.ssh/id_ed25519",
+                "correct horse 'battery'",
+                &metadata,
+            )
+            .unwrap();
+
+        let argv = stand_in.argv();
+        assert!(!argv.contains("correct horse"));
+        assert!(!argv.contains("id_ed25519"));
+        let input = stand_in.input();
+        assert_eq!(input["secret"], "correct horse 'battery'");
+        assert_eq!(input["target"], "winaskpass:/home/user/.ssh/id_ed25519");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_target_sent_on_stdin_when_reading() {
+        let output = read_output("", serde_json::json!({}), "secret");
+        let stand_in = powershell::stand_in::StandIn::new("get", &output);
+        let store = PowerShellStore {
+            program: stand_in.program.clone(),
+        };
+
+        let credential = store.get("https://example.com/repo").unwrap().unwrap();
+        assert_eq!(credential.secret.expose(), "secret");
+        assert!(!stand_in.argv().contains("example.com"));
+        assert_eq!(
+            stand_in.input()["target"],
+            "winaskpass:https://example.com/repo"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unicode_password_read_verbatim() {
+        let password = " pässwörd 🔑 密码\n ";
+        let stand_in = powershell::stand_in::StandIn::new(
+            "get-unicode",
+            &read_output("/home/josé/.ssh/id_rsa", serde_json::json!({}), password),
+        );
+        let store = PowerShellStore {
+            program: stand_in.program.clone(),
+        };
 
-$results = [CredentialLister]::List('{prefix}')
-$results -join "`n"
-"#,
-        prefix = CREDENTIAL_PREFIX.replace("'", "''")
-    );
-
-    let output = powershell(&script)?;
-    if output.is_empty() {
-        Ok(Vec::new())
-    } else {
-        Ok(output.lines().map(|s| s.to_string()).collect())
+        let credential = store.get("/home/josé/.ssh/id_rsa").unwrap().unwrap();
+        assert_eq!(credential.secret.expose(), password);
+        assert_eq!(
+            credential.metadata.key_path.as_deref(),
+            Some("/home/josé/.ssh/id_rsa")
+        );
     }
 }
@@ -0,0 +1,296 @@
+use crate::config;
+use crate::error::Error;
+use crate::secret::Secret;
+use anyhow::Result;
+use std::env;
+#[cfg(unix)]
+use std::fs;
+use std::path::Path;
+
+pub struct PromptResult {
+    pub password: Secret,
+    pub save: bool,
+}
+
+/// A message left on screen until it is dropped.
+pub trait Notification {
+    /// Whether the user already dismissed the notification.
+    fn is_dismissed(&mut self) -> bool;
+}
+
+/// A way of asking the user for passwords and decisions.
+pub trait Prompter {
+    fn name(&self) -> &'static str;
+
+    /// Whether the backend can show prompts in this environment.
+    fn is_available(&self) -> bool;
+
+    /// Shows the password dialog titled `caption`. With `retry` set the dialog
+    /// reports that the previous passphrase was incorrect. Returns None if the
+    /// user cancelled.
+    fn prompt_password(
+        &self,
+        caption: &str,
+        prompt: &str,
+        show_save_checkbox: bool,
+        retry: bool,
+    ) -> Result<Option<PromptResult>>;
+
+    /// Asks for a value that may be shown while it is typed, such as a user
+    /// name. Backends without a visible input field hide it like a password.
+    /// Returns None if the user cancelled.
+    fn prompt_text(&self, caption: &str, prompt: &str) -> Result<Option<Secret>> {
+        Ok(self
+            .prompt_password(caption, prompt, false, false)?
+            .map(|result| result.password))
+    }
+
+    /// Shows a confirmation dialog titled `caption` with Yes/No/Cancel
+    /// buttons. Returns Some("yes"), Some("no"), or None if cancelled.
+    fn prompt_confirmation(&self, caption: &str, prompt: &str) -> Result<Option<String>>;
+
+    /// Asks the user to allow or deny an operation in a dialog titled
+    /// `caption`, defaulting to deny. Returns true only if the user allowed it.
+    fn prompt_allow(&self, caption: &str, message: &str) -> Result<bool>;
+
+    /// Shows a message without waiting for the user to dismiss it.
+    fn notify(&self, message: &str) -> Result<Box<dyn Notification>>;
+
+    /// Shows a message titled `caption` with only an OK button, returning
+    /// once the user dismissed it.
+    fn show_message(&self, caption: &str, message: &str) -> Result<()>;
+}
+
+/// Every compiled-in backend, in the default order of preference.
+fn backends() -> Vec<Box<dyn Prompter>> {
+    vec![
+        #[cfg(feature = "native")]
+        Box::new(crate::dialog_native::NativePrompter),
+        #[cfg(feature = "powershell")]
+        Box::new(crate::dialog_powershell::PowerShellPrompter::default()),
+        #[cfg(unix)]
+        Box::new(crate::dialog_tty::TtyPrompter),
+        Box::new(crate::dialog_pinentry::PinentryPrompter::default()),
+    ]
+}
+
+/// Whether dialogs can reach a desktop. On WSL that takes interop, and the
+/// Windows programs it starts open on the Windows desktop however the shell
+/// was reached, SSH included. Elsewhere an SSH login has none: dialogs would
+/// open on the remote machine's screen if at all. The `desktop` setting,
+/// `on` or `off`, overrides the detection.
+pub fn has_desktop() -> bool {
+    let ssh_login = env::var_os("SSH_CONNECTION").is_some() || env::var_os("SSH_TTY").is_some();
+    desktop_available(config::get("desktop").as_deref(), ssh_login, wsl_interop())
+}
+
+fn desktop_available(setting: Option<&str>, ssh_login: bool, wsl_interop: Option<bool>) -> bool {
+    // Without interop no Windows program starts, whatever the setting
+    if wsl_interop == Some(false) {
+        return false;
+    }
+    match setting {
+        Some("on") => true,
+        Some("off") => false,
+        _ => wsl_interop.is_some() || !ssh_login,
+    }
+}
+
+/// Whether `program` can be spawned, either as a path or found in `PATH`.
+pub fn is_executable_in_path(program: &Path) -> bool {
+    if program.components().count() > 1 {
+        return program.is_file();
+    }
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+/// Whether WSL can start Windows programs, None outside WSL.
+#[cfg(unix)]
+fn wsl_interop() -> Option<bool> {
+    let is_wsl = env::var_os("WSL_DISTRO_NAME").is_some()
+        || fs::read_to_string("/proc/sys/kernel/osrelease")
+            .is_ok_and(|release| release.to_lowercase().contains("microsoft"));
+    if !is_wsl {
+        return None;
+    }
+    // Registered as a binfmt_misc handler only while interop is enabled
+    Some(["WSLInterop", "WSLInterop-late"].iter().any(|name| {
+        fs::read_to_string(Path::new("/proc/sys/fs/binfmt_misc").join(name))
+            .is_ok_and(|status| status.lines().next() == Some("enabled"))
+    }))
+}
+
+#[cfg(not(unix))]
+fn wsl_interop() -> Option<bool> {
+    None
+}
+
+/// Picks the first available backend. The `dialog` setting, a comma
+/// separated list of backend names, overrides the order and the candidates.
+pub fn select() -> Result<Box<dyn Prompter>> {
+    let order = config::get("dialog");
+    select_from(backends(), order.as_deref())
+}
+
+/// Like [`select`], leaving out the backend called `name`.
+pub fn select_except(name: &str) -> Result<Box<dyn Prompter>> {
+    let mut backends = backends();
+    backends.retain(|backend| backend.name() != name);
+    let order = config::get("dialog");
+    select_from(backends, order.as_deref())
+}
+
+fn select_from(
+    mut backends: Vec<Box<dyn Prompter>>,
+    order: Option<&str>,
+) -> Result<Box<dyn Prompter>> {
+    if let Some(order) = order {
+        let names: Vec<&str> = order.split(',').map(str::trim).collect();
+        backends.retain(|backend| names.contains(&backend.name()));
+        backends.sort_by_key(|backend| names.iter().position(|name| *name == backend.name()));
+    }
+
+    match backends.into_iter().find(|backend| backend.is_available()) {
+        Some(backend) => Ok(backend),
+        None => Err(Error::Unavailable("No dialog backend available".to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+pub mod scripted {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Prompter giving canned answers and recording what it was asked, for tests.
+    pub struct ScriptedPrompter {
+        pub name: &'static str,
+        pub available: bool,
+        /// Answer to password prompts: the password and the save checkbox
+        pub password: Option<(&'static str, bool)>,
+        pub allow: bool,
+        /// Answer to confirmation prompts
+        pub confirmation: Option<&'static str>,
+        /// `prompt_password` calls as (prompt, show_save_checkbox, retry)
+        pub password_calls: RefCell<Vec<(String, bool, bool)>>,
+        /// Captions of the password and confirmation dialogs, in order
+        pub captions: RefCell<Vec<String>>,
+        /// `show_message` calls as (caption, message)
+        pub messages: RefCell<Vec<(String, String)>>,
+    }
+
+    impl Default for ScriptedPrompter {
+        fn default() -> Self {
+            ScriptedPrompter {
+                name: "scripted",
+                available: true,
+                password: None,
+                allow: false,
+                confirmation: None,
+                password_calls: RefCell::new(Vec::new()),
+                captions: RefCell::new(Vec::new()),
+                messages: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Prompter for ScriptedPrompter {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn is_available(&self) -> bool {
+            self.available
+        }
+
+        fn prompt_password(
+            &self,
+            caption: &str,
+            prompt: &str,
+            show_save_checkbox: bool,
+            retry: bool,
+        ) -> Result<Option<PromptResult>> {
+            self.captions.borrow_mut().push(caption.to_string());
+            self.password_calls
+                .borrow_mut()
+                .push((prompt.to_string(), show_save_checkbox, retry));
+            Ok(self.password.map(|(password, save)| PromptResult {
+                password: Secret::from(password.to_string()),
+                save,
+            }))
+        }
+
+        fn prompt_confirmation(&self, caption: &str, _: &str) -> Result<Option<String>> {
+            self.captions.borrow_mut().push(caption.to_string());
+            Ok(self.confirmation.map(str::to_string))
+        }
+
+        fn prompt_allow(&self, caption: &str, _: &str) -> Result<bool> {
+            self.captions.borrow_mut().push(caption.to_string());
+            Ok(self.allow)
+        }
+
+        fn notify(&self, _: &str) -> Result<Box<dyn Notification>> {
+            anyhow::bail!("notifications are not scripted")
+        }
+
+        fn show_message(&self, caption: &str, message: &str) -> Result<()> {
+            self.messages
+                .borrow_mut()
+                .push((caption.to_string(), message.to_string()));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scripted::ScriptedPrompter;
+    use super::*;
+
+    fn fakes(available: &[(&'static str, bool)]) -> Vec<Box<dyn Prompter>> {
+        available
+            .iter()
+            .map(|&(name, available)| {
+                Box::new(ScriptedPrompter {
+                    name,
+                    available,
+                    ..Default::default()
+                }) as _
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_select_first_available() {
+        let backends = fakes(&[("native", false), ("powershell", true), ("tty", true)]);
+        assert_eq!(select_from(backends, None).unwrap().name(), "powershell");
+    }
+
+    #[test]
+    fn test_select_honors_configured_order() {
+        let backends = fakes(&[("native", true), ("powershell", true), ("tty", true)]);
+        let selected = select_from(backends, Some("tty, native")).unwrap();
+        assert_eq!(selected.name(), "tty");
+    }
+
+    #[test]
+    fn test_select_none_available() {
+        let backends = fakes(&[("native", false), ("powershell", true)]);
+        assert!(select_from(backends, Some("native")).is_err());
+    }
+
+    #[test]
+    fn test_desktop_over_ssh_on_wsl() {
+        // WSL with interop reaches the Windows desktop from an SSH login too
+        assert!(desktop_available(None, true, Some(true)));
+        assert!(!desktop_available(None, true, Some(false)));
+        assert!(!desktop_available(Some("off"), false, Some(true)));
+        assert!(!desktop_available(Some("on"), false, Some(false)));
+
+        // Elsewhere an SSH login has no desktop unless configured
+        assert!(desktop_available(None, false, None));
+        assert!(!desktop_available(None, true, None));
+        assert!(desktop_available(Some("on"), true, None));
+    }
+}
@@ -1,6 +1,11 @@
+use crate::dialog::{self, Notification, PromptResult, Prompter};
+use crate::secret::{self, Secret, SecretBuf};
 use anyhow::Result;
 use std::ptr;
-use windows::Win32::Foundation::{ERROR_CANCELLED, HWND};
+use std::thread::{self, JoinHandle};
+use windows::Win32::Foundation::{
+    ERROR_CANCELLED, ERROR_INSUFFICIENT_BUFFER, ERROR_LOGON_FAILURE, HWND,
+};
 use windows::Win32::Security::Credentials::{
     CRED_PACK_FLAGS, CREDUI_INFOW, CREDUIWIN_CHECKBOX, CREDUIWIN_FLAGS, CREDUIWIN_GENERIC,
     CREDUIWIN_IN_CRED_ONLY, CredPackAuthenticationBufferW, CredUIPromptForWindowsCredentialsW,
@@ -8,145 +13,246 @@ use windows::Win32::Security::Credentials::{
 };
 use windows::Win32::System::Com::CoTaskMemFree;
 use windows::Win32::UI::WindowsAndMessaging::{
-    IDCANCEL, IDNO, IDYES, MB_ICONWARNING, MB_YESNOCANCEL, MESSAGEBOX_STYLE, MessageBoxW,
+    IDCANCEL, IDNO, IDYES, MB_DEFBUTTON2, MB_ICONINFORMATION, MB_ICONQUESTION, MB_ICONWARNING,
+    MB_OK, MB_YESNO, MB_YESNOCANCEL, MESSAGEBOX_STYLE, MessageBoxW,
 };
 use windows::core::{PCWSTR, PWSTR};
 
-pub struct PromptResult {
-    pub password: String,
-    pub save: bool,
+/// Dialogs shown by calling the Windows API directly.
+pub struct NativePrompter;
+
+/// A notification shown from a background thread. The message box goes
+/// away together with the process.
+struct NativeNotification {
+    thread: JoinHandle<()>,
 }
 
-/// Shows a confirmation dialog with Yes/No/Cancel buttons.
-/// Returns Some("yes"), Some("no"), or None if cancelled.
-pub fn prompt_confirmation(prompt: &str) -> Result<Option<String>> {
-    let prompt_wide: Vec<u16> = prompt.encode_utf16().chain(std::iter::once(0)).collect();
-    let title_wide: Vec<u16> = "SSH Host Verification"
-        .encode_utf16()
-        .chain(std::iter::once(0))
-        .collect();
-
-    unsafe {
-        let result = MessageBoxW(
-            None,
-            PCWSTR(prompt_wide.as_ptr()),
-            PCWSTR(title_wide.as_ptr()),
-            MESSAGEBOX_STYLE(MB_YESNOCANCEL.0 | MB_ICONWARNING.0),
-        );
+impl Notification for NativeNotification {
+    fn is_dismissed(&mut self) -> bool {
+        self.thread.is_finished()
+    }
+}
 
-        match result {
-            IDYES => Ok(Some("yes".to_string())),
-            IDNO => Ok(Some("no".to_string())),
-            IDCANCEL => Ok(None),
-            _ => Ok(None),
+impl Prompter for NativePrompter {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn is_available(&self) -> bool {
+        dialog::has_desktop()
+    }
+
+    fn prompt_confirmation(&self, caption: &str, prompt: &str) -> Result<Option<String>> {
+        let prompt_wide: Vec<u16> = prompt.encode_utf16().chain(std::iter::once(0)).collect();
+        let title_wide: Vec<u16> = caption.encode_utf16().chain(std::iter::once(0)).collect();
+
+        unsafe {
+            let result = MessageBoxW(
+                None,
+                PCWSTR(prompt_wide.as_ptr()),
+                PCWSTR(title_wide.as_ptr()),
+                MESSAGEBOX_STYLE(MB_YESNOCANCEL.0 | MB_ICONWARNING.0),
+            );
+
+            match result {
+                IDYES => Ok(Some("yes".to_string())),
+                IDNO => Ok(Some("no".to_string())),
+                IDCANCEL => Ok(None),
+                _ => Ok(None),
+            }
         }
     }
-}
 
-pub fn prompt_password(prompt: &str, show_save_checkbox: bool) -> Result<Option<PromptResult>> {
-    let caption = "SSH Key Passphrase";
-    let caption_wide: Vec<u16> = caption.encode_utf16().chain(std::iter::once(0)).collect();
-    let prompt_wide: Vec<u16> = prompt.encode_utf16().chain(std::iter::once(0)).collect();
-
-    unsafe {
-        let ui_info = CREDUI_INFOW {
-            cbSize: std::mem::size_of::<CREDUI_INFOW>() as u32,
-            hwndParent: HWND::default(),
-            pszMessageText: PCWSTR(prompt_wide.as_ptr()),
-            pszCaptionText: PCWSTR(caption_wide.as_ptr()),
-            hbmBanner: Default::default(),
-        };
+    fn prompt_allow(&self, caption: &str, message: &str) -> Result<bool> {
+        let message_wide: Vec<u16> = message.encode_utf16().chain(std::iter::once(0)).collect();
+        let title_wide: Vec<u16> = caption.encode_utf16().chain(std::iter::once(0)).collect();
 
-        // Pack initial credentials (empty username and password)
-        let username_wide: Vec<u16> = vec![0];
-        let password_wide: Vec<u16> = vec![0];
-
-        let mut in_buffer_size: u32 = 0;
-        let _ = CredPackAuthenticationBufferW(
-            CRED_PACK_FLAGS(0),
-            PWSTR(username_wide.as_ptr() as *mut u16),
-            PWSTR(password_wide.as_ptr() as *mut u16),
-            None,
-            &mut in_buffer_size,
-        );
-
-        let mut in_buffer = vec![0u8; in_buffer_size as usize];
-        let pack_result = CredPackAuthenticationBufferW(
-            CRED_PACK_FLAGS(0),
-            PWSTR(username_wide.as_ptr() as *mut u16),
-            PWSTR(password_wide.as_ptr() as *mut u16),
-            Some(in_buffer.as_mut_ptr()),
-            &mut in_buffer_size,
-        );
-
-        if pack_result.is_err() {
-            return Err(anyhow::anyhow!("Failed to pack authentication buffer"));
+        unsafe {
+            let result = MessageBoxW(
+                None,
+                PCWSTR(message_wide.as_ptr()),
+                PCWSTR(title_wide.as_ptr()),
+                MESSAGEBOX_STYLE(MB_YESNO.0 | MB_ICONQUESTION.0 | MB_DEFBUTTON2.0),
+            );
+
+            Ok(result == IDYES)
         }
+    }
+
+    fn notify(&self, message: &str) -> Result<Box<dyn Notification>> {
+        let message_wide: Vec<u16> = message.encode_utf16().chain(std::iter::once(0)).collect();
+        let title_wide: Vec<u16> = "Security Key"
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
 
-        let mut auth_package: u32 = 0;
-        let mut out_buffer: *mut std::ffi::c_void = ptr::null_mut();
-        let mut out_buffer_size: u32 = 0;
-        let mut save = false.into();
+        let thread = thread::spawn(move || unsafe {
+            MessageBoxW(
+                None,
+                PCWSTR(message_wide.as_ptr()),
+                PCWSTR(title_wide.as_ptr()),
+                MESSAGEBOX_STYLE(MB_OK.0 | MB_ICONINFORMATION.0),
+            );
+        });
 
-        let mut flags = CREDUIWIN_GENERIC.0 | CREDUIWIN_IN_CRED_ONLY.0;
-        if show_save_checkbox {
-            flags |= CREDUIWIN_CHECKBOX.0;
+        Ok(Box::new(NativeNotification { thread }))
+    }
+
+    fn show_message(&self, caption: &str, message: &str) -> Result<()> {
+        let message_wide: Vec<u16> = message.encode_utf16().chain(std::iter::once(0)).collect();
+        let caption_wide: Vec<u16> = caption.encode_utf16().chain(std::iter::once(0)).collect();
+
+        unsafe {
+            MessageBoxW(
+                None,
+                PCWSTR(message_wide.as_ptr()),
+                PCWSTR(caption_wide.as_ptr()),
+                MESSAGEBOX_STYLE(MB_OK.0 | MB_ICONINFORMATION.0),
+            );
         }
+        Ok(())
+    }
+
+    fn prompt_password(
+        &self,
+        caption: &str,
+        prompt: &str,
+        show_save_checkbox: bool,
+        retry: bool,
+    ) -> Result<Option<PromptResult>> {
+        let caption_wide: Vec<u16> = caption.encode_utf16().chain(std::iter::once(0)).collect();
+        let prompt_wide: Vec<u16> = prompt.encode_utf16().chain(std::iter::once(0)).collect();
 
-        let result = CredUIPromptForWindowsCredentialsW(
-            Some(&ui_info),
-            0,
-            &mut auth_package,
-            Some(in_buffer.as_ptr() as *const std::ffi::c_void),
-            in_buffer_size,
-            &mut out_buffer,
-            &mut out_buffer_size,
-            Some(&mut save),
-            CREDUIWIN_FLAGS(flags),
-        );
-
-        if result != 0 {
-            if result == ERROR_CANCELLED.0 {
-                return Ok(None);
+        unsafe {
+            let ui_info = CREDUI_INFOW {
+                cbSize: std::mem::size_of::<CREDUI_INFOW>() as u32,
+                hwndParent: HWND::default(),
+                pszMessageText: PCWSTR(prompt_wide.as_ptr()),
+                pszCaptionText: PCWSTR(caption_wide.as_ptr()),
+                hbmBanner: Default::default(),
+            };
+
+            // Pack initial credentials (empty username and password)
+            let username_wide: Vec<u16> = vec![0];
+            let password_wide: Vec<u16> = vec![0];
+
+            let mut in_buffer_size: u32 = 0;
+            let _ = CredPackAuthenticationBufferW(
+                CRED_PACK_FLAGS(0),
+                PWSTR(username_wide.as_ptr() as *mut u16),
+                PWSTR(password_wide.as_ptr() as *mut u16),
+                None,
+                &mut in_buffer_size,
+            );
+
+            let mut in_buffer = vec![0u8; in_buffer_size as usize];
+            let pack_result = CredPackAuthenticationBufferW(
+                CRED_PACK_FLAGS(0),
+                PWSTR(username_wide.as_ptr() as *mut u16),
+                PWSTR(password_wide.as_ptr() as *mut u16),
+                Some(in_buffer.as_mut_ptr()),
+                &mut in_buffer_size,
+            );
+
+            if pack_result.is_err() {
+                return Err(anyhow::anyhow!("Failed to pack authentication buffer"));
+            }
+
+            let mut auth_package: u32 = 0;
+            let mut out_buffer: *mut std::ffi::c_void = ptr::null_mut();
+            let mut out_buffer_size: u32 = 0;
+            let mut save = false.into();
+
+            let mut flags = CREDUIWIN_GENERIC.0 | CREDUIWIN_IN_CRED_ONLY.0;
+            if show_save_checkbox {
+                flags |= CREDUIWIN_CHECKBOX.0;
+            }
+
+            let auth_error = if retry { ERROR_LOGON_FAILURE.0 } else { 0 };
+
+            let result = CredUIPromptForWindowsCredentialsW(
+                Some(&ui_info),
+                auth_error,
+                &mut auth_package,
+                Some(in_buffer.as_ptr() as *const std::ffi::c_void),
+                in_buffer_size,
+                &mut out_buffer,
+                &mut out_buffer_size,
+                Some(&mut save),
+                CREDUIWIN_FLAGS(flags),
+            );
+
+            if result != 0 {
+                if result == ERROR_CANCELLED.0 {
+                    return Ok(None);
+                }
+                return Err(anyhow::anyhow!(
+                    "CredUIPromptForWindowsCredentialsW failed with error code: {}",
+                    result
+                ));
             }
-            return Err(anyhow::anyhow!(
-                "CredUIPromptForWindowsCredentialsW failed with error code: {}",
-                result
+
+            let password = unpack_password(out_buffer, out_buffer_size);
+            secret::wipe(std::slice::from_raw_parts_mut(
+                out_buffer as *mut u8,
+                out_buffer_size as usize,
             ));
-        }
+            CoTaskMemFree(Some(out_buffer as *const _));
 
-        // Unpack the result
-        let mut user_buf = vec![0u16; 256];
-        let mut user_len: u32 = user_buf.len() as u32;
-        let mut domain_buf = vec![0u16; 256];
-        let mut domain_len: u32 = domain_buf.len() as u32;
-        let mut pass_buf = vec![0u16; 256];
-        let mut pass_len: u32 = pass_buf.len() as u32;
-
-        let unpack_result = CredUnPackAuthenticationBufferW(
-            CRED_PACK_FLAGS(0),
-            out_buffer as *const _,
-            out_buffer_size,
-            Some(PWSTR(user_buf.as_mut_ptr())),
-            &mut user_len,
-            Some(PWSTR(domain_buf.as_mut_ptr())),
-            Some(&mut domain_len),
-            Some(PWSTR(pass_buf.as_mut_ptr())),
-            &mut pass_len,
-        );
-
-        CoTaskMemFree(Some(out_buffer as *const _));
-
-        if unpack_result.is_err() {
-            return Err(anyhow::anyhow!("Failed to unpack authentication buffer"));
+            Ok(Some(PromptResult {
+                password: password?,
+                save: save.as_bool(),
+            }))
         }
+    }
+}
+
+/// Extracts the password from the buffer returned by the dialog. The buffers
+/// start at 256 units and grow to the size the API asks for.
+unsafe fn unpack_password(buffer: *const std::ffi::c_void, size: u32) -> Result<Secret> {
+    let mut user_len: u32 = 256;
+    let mut domain_len: u32 = 256;
+    let mut pass_len: u32 = 256;
 
-        // Extract password from buffer
-        let password = String::from_utf16_lossy(&pass_buf[..pass_len.saturating_sub(1) as usize]);
+    loop {
+        let mut user_buf = vec![0u16; user_len as usize];
+        let mut domain_buf = vec![0u16; domain_len as usize];
+        let mut pass_buf = SecretBuf::<u16>::zeroed(pass_len as usize);
 
-        Ok(Some(PromptResult {
-            password,
-            save: save.as_bool(),
-        }))
+        let result = unsafe {
+            CredUnPackAuthenticationBufferW(
+                CRED_PACK_FLAGS(0),
+                buffer,
+                size,
+                Some(PWSTR(user_buf.as_mut_ptr())),
+                &mut user_len,
+                Some(PWSTR(domain_buf.as_mut_ptr())),
+                Some(&mut domain_len),
+                Some(PWSTR(pass_buf.as_mut_ptr())),
+                &mut pass_len,
+            )
+        };
+
+        match result {
+            // The length includes the terminating null
+            Ok(()) => {
+                return Ok(Secret::from_utf16(
+                    &pass_buf[..pass_len.saturating_sub(1) as usize],
+                ));
+            }
+            // The lengths now hold the sizes needed; retry unless nothing
+            // would grow
+            Err(e)
+                if e.code() == ERROR_INSUFFICIENT_BUFFER.to_hresult()
+                    && (user_len as usize > user_buf.len()
+                        || domain_len as usize > domain_buf.len()
+                        || pass_len as usize > pass_buf.len()) =>
+            {
+                user_len = user_len.max(user_buf.len() as u32);
+                domain_len = domain_len.max(domain_buf.len() as u32);
+                pass_len = pass_len.max(pass_buf.len() as u32);
+            }
+            Err(e) => anyhow::bail!("Failed to unpack authentication buffer: {}", e),
+        }
     }
 }
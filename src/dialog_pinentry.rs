@@ -0,0 +1,508 @@
+use crate::config;
+use crate::dialog::{self, Notification, PromptResult, Prompter};
+use crate::error::Error;
+use crate::secret::{Secret, SecretBuf};
+use anyhow::{Context, Result};
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// libgpg-error codes, found in the low 16 bits of an `ERR` reply.
+const GPG_ERR_CANCELED: u32 = 99;
+const GPG_ERR_NOT_CONFIRMED: u32 = 114;
+
+/// Dialogs shown by a pinentry program such as `pinentry-gnome3` or
+/// `pinentry-curses`, talking the Assuan protocol.
+pub struct PinentryPrompter {
+    program: PathBuf,
+}
+
+impl Default for PinentryPrompter {
+    fn default() -> Self {
+        PinentryPrompter {
+            program: PathBuf::from(
+                config::get("pinentry").unwrap_or_else(|| "pinentry".to_string()),
+            ),
+        }
+    }
+}
+
+impl PinentryPrompter {
+    /// Uses `program` in place of the configured pinentry.
+    #[cfg(test)]
+    pub fn with_program(program: &std::path::Path) -> Self {
+        PinentryPrompter {
+            program: program.to_path_buf(),
+        }
+    }
+}
+
+/// Escapes `value` for use as a command parameter.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '%' | '\r' | '\n' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Appends the `%XX` escaped data of a `D` line to `data`. Moves to a new
+/// buffer rather than growing in place, so no copy is left behind.
+fn unescape_into(data: &mut SecretBuf<u8>, line: &str) -> Result<()> {
+    // Decoding only shortens the line, so this never reallocates
+    let mut decoded = Vec::with_capacity(data.len() + line.len());
+    decoded.extend_from_slice(data);
+    let mut valid = true;
+    let mut rest = line.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            decoded.push(byte);
+            rest = tail;
+            continue;
+        }
+        match tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            Some(value) => decoded.push(value),
+            None => {
+                valid = false;
+                break;
+            }
+        }
+        rest = &tail[2..];
+    }
+
+    let decoded = SecretBuf::from(decoded);
+    if !valid {
+        return Err(Error::MalformedOutput("Invalid escape from pinentry".to_string()).into());
+    }
+    *data = decoded;
+    Ok(())
+}
+
+/// A running pinentry. Dropping it closes stdin, which pinentry takes as
+/// BYE, and then waits for it to exit.
+struct Session {
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    child: Process,
+}
+
+/// Waits for the process when dropped, so it is not left behind as a zombie.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.wait();
+    }
+}
+
+impl Session {
+    fn start(program: &std::path::Path) -> Result<Session> {
+        let mut child = Command::new(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                Error::Unavailable(format!("Failed to execute {}: {}", program.display(), e))
+            })?;
+        let stdin = child.stdin.take().context("pinentry stdin unavailable")?;
+        let stdout = BufReader::new(child.stdout.take().context("pinentry stdout unavailable")?);
+
+        let mut session = Session {
+            stdin,
+            stdout,
+            child: Process(child),
+        };
+        session
+            .reply()?
+            .map_err(|(code, _)| anyhow::anyhow!("pinentry failed to start: {}", code))?;
+
+        // A terminal pinentry needs to know where to draw
+        #[cfg(unix)]
+        if let Some(tty) = env::var("GPG_TTY").ok().or_else(|| {
+            std::fs::File::open("/dev/tty")
+                .ok()
+                .map(|_| "/dev/tty".to_string())
+        }) {
+            session.option(&format!("ttyname={}", tty))?;
+            if let Ok(term) = env::var("TERM") {
+                session.option(&format!("ttytype={}", term))?;
+            }
+        }
+        Ok(session)
+    }
+
+    /// Sends `command` and waits for the reply.
+    fn command(&mut self, command: &str) -> Result<Reply> {
+        writeln!(self.stdin, "{}", command).context("Failed to write to pinentry")?;
+        self.stdin.flush()?;
+        self.reply()
+    }
+
+    /// Sends `<command> <value>`, failing unless pinentry accepts it.
+    fn set(&mut self, command: &str, value: &str) -> Result<()> {
+        match self.command(&format!("{} {}", command, escape(value)))? {
+            Ok(_) => Ok(()),
+            Err((code, message)) => {
+                anyhow::bail!("pinentry {} failed: {} {}", command, code, message)
+            }
+        }
+    }
+
+    /// Sets an option, ignoring ones the pinentry does not know.
+    fn option(&mut self, option: &str) -> Result<()> {
+        self.command(&format!("OPTION {}", option))?.ok();
+        Ok(())
+    }
+
+    /// Reads a reply: the data lines up to `OK`, or the `ERR` code and
+    /// message.
+    fn reply(&mut self) -> Result<Reply> {
+        let mut data = SecretBuf::from(Vec::new());
+        loop {
+            let mut line = String::new();
+            if self.stdout.read_line(&mut line)? == 0 {
+                return Err(
+                    Error::MalformedOutput("pinentry closed the connection".to_string()).into(),
+                );
+            }
+            // Data lines may hold the PIN
+            let line = Secret::from(line);
+            let line = line.expose().trim_end_matches(['\r', '\n']);
+
+            if line == "OK" || line.starts_with("OK ") {
+                return Ok(Ok(Secret::from_utf8(data.to_vec())));
+            } else if let Some(error) = line.strip_prefix("ERR ") {
+                let (code, message) = error.split_once(' ').unwrap_or((error, ""));
+                let code = code.parse::<u32>().map_err(|_| {
+                    Error::MalformedOutput(format!("Invalid error from pinentry: {}", line))
+                })?;
+                return Ok(Err((code & 0xffff, message.to_string())));
+            } else if let Some(chunk) = line.strip_prefix("D ") {
+                unescape_into(&mut data, chunk)?;
+            } else if line.starts_with("INQUIRE") {
+                // Nothing to provide
+                writeln!(self.stdin, "CAN")?;
+                self.stdin.flush()?;
+            } else if line.starts_with("S ") || line.starts_with('#') || line.is_empty() {
+                // Status and comment lines carry nothing needed here
+            } else {
+                return Err(Error::MalformedOutput(format!(
+                    "Unexpected line from pinentry: {}",
+                    line
+                ))
+                .into());
+            }
+        }
+    }
+
+    /// Asks a CONFIRM question titled `title` with the given buttons.
+    fn confirm(
+        &mut self,
+        title: &str,
+        description: &str,
+        ok: &str,
+        not_ok: Option<&str>,
+        cancel: &str,
+    ) -> Result<Reply> {
+        self.command("RESET")?.ok();
+        self.set("SETTITLE", title)?;
+        self.set("SETDESC", description)?;
+        self.set("SETOK", ok)?;
+        if let Some(not_ok) = not_ok {
+            self.set("SETNOTOK", not_ok)?;
+        }
+        self.set("SETCANCEL", cancel)?;
+        self.command("CONFIRM")
+    }
+}
+
+/// The data of an `OK` reply, or the error code and message of an `ERR`.
+type Reply = std::result::Result<Secret, (u32, String)>;
+
+/// A MESSAGE dialog, closed when dropped.
+struct PinentryNotification {
+    child: Process,
+    thread: JoinHandle<()>,
+}
+
+impl Notification for PinentryNotification {
+    fn is_dismissed(&mut self) -> bool {
+        self.thread.is_finished()
+    }
+}
+
+impl Drop for PinentryNotification {
+    fn drop(&mut self) {
+        let _ = self.child.0.kill();
+    }
+}
+
+impl Prompter for PinentryPrompter {
+    fn name(&self) -> &'static str {
+        "pinentry"
+    }
+
+    fn is_available(&self) -> bool {
+        dialog::is_executable_in_path(&self.program)
+    }
+
+    fn prompt_password(
+        &self,
+        caption: &str,
+        prompt: &str,
+        show_save_checkbox: bool,
+        retry: bool,
+    ) -> Result<Option<PromptResult>> {
+        let mut session = Session::start(&self.program)?;
+        session.set("SETTITLE", caption)?;
+        session.set("SETDESC", prompt)?;
+        session.set("SETPROMPT", "Passphrase:")?;
+        if retry {
+            session.set("SETERROR", "Bad passphrase, try again")?;
+        }
+
+        let password = match session.command("GETPIN")? {
+            Ok(password) => password,
+            Err((GPG_ERR_CANCELED, _)) => return Ok(None),
+            Err((code, message)) => anyhow::bail!("pinentry GETPIN failed: {} {}", code, message),
+        };
+
+        // pinentry has no checkbox, so ask separately
+        let save = show_save_checkbox
+            && session
+                .confirm(
+                    caption,
+                    "Save the passphrase in Windows Credential Manager?",
+                    "Save",
+                    None,
+                    "Don't save",
+                )?
+                .is_ok();
+        Ok(Some(PromptResult { password, save }))
+    }
+
+    fn prompt_confirmation(&self, caption: &str, prompt: &str) -> Result<Option<String>> {
+        let mut session = Session::start(&self.program)?;
+        match session.confirm(caption, prompt, "Yes", Some("No"), "Cancel")? {
+            Ok(_) => Ok(Some("yes".to_string())),
+            Err((GPG_ERR_NOT_CONFIRMED, _)) => Ok(Some("no".to_string())),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn prompt_allow(&self, caption: &str, message: &str) -> Result<bool> {
+        let mut session = Session::start(&self.program)?;
+        Ok(session
+            .confirm(caption, message, "Allow", None, "Deny")?
+            .is_ok())
+    }
+
+    fn notify(&self, message: &str) -> Result<Box<dyn Notification>> {
+        let mut session = Session::start(&self.program)?;
+        session.set("SETTITLE", "Security Key")?;
+        session.set("SETDESC", message)?;
+        writeln!(session.stdin, "MESSAGE")?;
+        session.stdin.flush()?;
+
+        // The reply arrives once the message is dismissed
+        let Session {
+            child,
+            stdin,
+            mut stdout,
+        } = session;
+        let thread = thread::spawn(move || {
+            let _stdin = stdin;
+            let mut line = String::new();
+            while stdout.read_line(&mut line).is_ok_and(|read| read > 0) {
+                if line.starts_with("OK") || line.starts_with("ERR") {
+                    break;
+                }
+                line.clear();
+            }
+        });
+        Ok(Box::new(PinentryNotification { child, thread }))
+    }
+
+    fn show_message(&self, caption: &str, message: &str) -> Result<()> {
+        let mut session = Session::start(&self.program)?;
+        session.set("SETTITLE", caption)?;
+        session.set("SETDESC", message)?;
+        // Replies once the message is dismissed, whichever way
+        session.command("MESSAGE")?.ok();
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    /// A fake pinentry answering GETPIN and CONFIRM with canned replies and
+    /// recording the commands it got.
+    struct FakePinentry {
+        dir: PathBuf,
+    }
+
+    impl FakePinentry {
+        fn new(name: &str, getpin: &str, confirm: &str) -> FakePinentry {
+            let dir = env::temp_dir().join(format!(
+                "winaskpass-pinentry-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("getpin"), getpin).unwrap();
+            fs::write(dir.join("confirm"), confirm).unwrap();
+
+            let program = dir.join("pinentry");
+            let script = concat!(
+                "#!/bin/sh\n",
+                "dir=\"$(dirname \"$0\")\"\n",
+                "echo 'OK Pleased to meet you'\n",
+                "while read -r line; do\n",
+                "  echo \"$line\" >> \"$dir/commands\"\n",
+                "  case \"$line\" in\n",
+                "    GETPIN) cat \"$dir/getpin\" ;;\n",
+                "    CONFIRM) cat \"$dir/confirm\" ;;\n",
+                "    BYE) echo OK; exit 0 ;;\n",
+                "    *) echo OK ;;\n",
+                "  esac\n",
+                "done\n",
+            );
+            fs::write(&program, script).unwrap();
+            fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+            FakePinentry { dir }
+        }
+
+        fn prompter(&self) -> PinentryPrompter {
+            PinentryPrompter::with_program(&self.dir.join("pinentry"))
+        }
+
+        fn commands(&self) -> Vec<String> {
+            fs::read_to_string(self.dir.join("commands"))
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    impl Drop for FakePinentry {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn test_password_unescaped_and_saved_on_ok() {
+        let pinentry = FakePinentry::new(
+            "password",
+            "S PASSWORD_FROM_CACHE\nD  pass%25word%0A \nOK\n",
+            "OK\n",
+        );
+
+        let prompt = "Bad passphrase, try again for /home/user/.ssh/id_rsa:\n100% sure";
+        let result = pinentry
+            .prompter()
+            .prompt_password("SSH Key Passphrase", prompt, true, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.password.expose(), " pass%word\n ");
+        assert!(result.save);
+
+        let commands = pinentry.commands();
+        assert!(
+            commands.contains(
+                &"SETDESC Bad passphrase, try again for /home/user/.ssh/id_rsa:%0A100%25 sure"
+                    .to_string()
+            )
+        );
+        assert!(commands.contains(&"SETERROR Bad passphrase, try again".to_string()));
+        assert!(commands.contains(&"CONFIRM".to_string()));
+        // The save question keeps the caption of the password dialog
+        let titles: Vec<_> = commands
+            .iter()
+            .filter(|command| command.starts_with("SETTITLE "))
+            .collect();
+        assert_eq!(titles, ["SETTITLE SSH Key Passphrase"; 2]);
+    }
+
+    #[test]
+    fn test_password_cancelled() {
+        let pinentry = FakePinentry::new(
+            "cancel",
+            "ERR 83886179 Operation cancelled <Pinentry>\n",
+            "OK\n",
+        );
+        assert!(
+            pinentry
+                .prompter()
+                .prompt_password("SSH Key Passphrase", "Enter passphrase: ", false, false)
+                .unwrap()
+                .is_none()
+        );
+        assert!(!pinentry.commands().contains(&"CONFIRM".to_string()));
+    }
+
+    #[test]
+    fn test_host_confirmation_buttons() {
+        let prompt = "The authenticity of host 'example.com' can't be established.";
+
+        let yes = FakePinentry::new("yes", "", "OK\n");
+        assert_eq!(
+            yes.prompter()
+                .prompt_confirmation("SSH Host Verification", prompt)
+                .unwrap()
+                .as_deref(),
+            Some("yes")
+        );
+        let commands = yes.commands();
+        assert!(commands.contains(&"SETTITLE SSH Host Verification".to_string()));
+        assert!(commands.contains(&"SETOK Yes".to_string()));
+        assert!(commands.contains(&"SETNOTOK No".to_string()));
+
+        let no = FakePinentry::new("no", "", "ERR 83886194 Not confirmed <Pinentry>\n");
+        assert_eq!(
+            no.prompter()
+                .prompt_confirmation("SSH Host Verification", prompt)
+                .unwrap()
+                .as_deref(),
+            Some("no")
+        );
+
+        let cancel = FakePinentry::new(
+            "host-cancel",
+            "",
+            "ERR 83886179 Operation cancelled <Pinentry>\n",
+        );
+        assert_eq!(
+            cancel
+                .prompter()
+                .prompt_confirmation("SSH Host Verification", prompt)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_missing_program_is_unavailable() {
+        let prompter = PinentryPrompter::with_program(Path::new("/nonexistent/pinentry"));
+        assert!(!prompter.is_available());
+        let error = prompter
+            .prompt_allow("SSH Key Confirmation", "Allow?")
+            .unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 3);
+    }
+}
@@ -1,111 +1,222 @@
-use anyhow::{Context, Result};
-use std::process::Command;
+use crate::dialog::{self, Notification, PromptResult, Prompter};
+use crate::error::Error;
+use crate::powershell;
+use crate::secret::Secret;
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::process::Child;
 
-pub struct PromptResult {
-    pub password: String,
-    pub save: bool,
+/// Dialogs shown through `powershell.exe`, usable from WSL.
+pub struct PowerShellPrompter {
+    program: PathBuf,
 }
 
-fn escape_ps_single_quoted(input: &str) -> String {
-    input.replace("'", "''")
+impl Default for PowerShellPrompter {
+    fn default() -> Self {
+        PowerShellPrompter {
+            program: PathBuf::from("powershell.exe"),
+        }
+    }
 }
 
-pub(crate) fn build_confirmation_script(prompt: &str) -> String {
-    format!(
-        r#"
+impl PowerShellPrompter {
+    /// Uses `program` in place of `powershell.exe`.
+    #[cfg(test)]
+    pub fn with_program(program: &std::path::Path) -> Self {
+        PowerShellPrompter {
+            program: program.to_path_buf(),
+        }
+    }
+}
+
+/// A notification shown by a `powershell.exe` child process, which is
+/// killed when the notification is closed.
+struct PowerShellNotification {
+    child: Child,
+}
+
+impl Notification for PowerShellNotification {
+    fn is_dismissed(&mut self) -> bool {
+        !matches!(self.child.try_wait(), Ok(None))
+    }
+}
+
+impl Drop for PowerShellNotification {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub(crate) const CONFIRMATION_SCRIPT: &str = r#"
 Add-Type -AssemblyName System.Windows.Forms
 $result = [System.Windows.Forms.MessageBox]::Show(
-    '{prompt}',
-    'SSH Host Verification',
+    $data.prompt,
+    $data.caption,
     [System.Windows.Forms.MessageBoxButtons]::YesNoCancel,
     [System.Windows.Forms.MessageBoxIcon]::Warning
 )
-switch ($result) {{
-    'Yes' {{ 'yes' }}
-    'No' {{ 'no' }}
-    default {{ '' }}
-}}
-"#,
-        prompt = escape_ps_single_quoted(prompt)
-    )
-}
-
-/// Shows a confirmation dialog with Yes/No/Cancel buttons.
-/// Returns Some("yes"), Some("no"), or None if cancelled.
-pub fn prompt_confirmation(prompt: &str) -> Result<Option<String>> {
-    // Use Windows MessageBox via PowerShell
-    let script = build_confirmation_script(prompt);
-
-    let output = Command::new("powershell.exe")
-        .args(["-NoProfile", "-Command", &script])
-        .output()
-        .context("Failed to execute PowerShell")?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("PowerShell error: {}", stderr);
-    }
-
-    let result = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    if result.is_empty() {
-        Ok(None)
-    } else {
-        Ok(Some(result))
-    }
-}
-
-pub fn prompt_password(prompt: &str, show_save_checkbox: bool) -> Result<Option<PromptResult>> {
-    // Use Windows CredUIPromptForWindowsCredentialsW via PowerShell
-    // This newer API supports both save checkbox and pre-filled username
-    let script = build_password_script(prompt, show_save_checkbox);
-
-    let output = Command::new("powershell.exe")
-        .args(["-NoProfile", "-Command", &script])
-        .output()
-        .context("Failed to execute PowerShell")?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        // Check if user cancelled
-        if stderr.contains("1223") || output.stdout.is_empty() {
-            return Ok(None);
+switch ($result) {
+    'Yes' { 'yes' }
+    'No' { 'no' }
+    default { '' }
+}
+"#;
+
+pub(crate) const ALLOW_SCRIPT: &str = r#"
+Add-Type -AssemblyName System.Windows.Forms
+$result = [System.Windows.Forms.MessageBox]::Show(
+    $data.message,
+    $data.caption,
+    [System.Windows.Forms.MessageBoxButtons]::YesNo,
+    [System.Windows.Forms.MessageBoxIcon]::Question,
+    [System.Windows.Forms.MessageBoxDefaultButton]::Button2
+)
+if ($result -eq 'Yes') { 'allow' }
+"#;
+
+const MESSAGE_SCRIPT: &str = r#"
+Add-Type -AssemblyName System.Windows.Forms
+[void][System.Windows.Forms.MessageBox]::Show(
+    $data.message,
+    $data.caption,
+    [System.Windows.Forms.MessageBoxButtons]::OK,
+    [System.Windows.Forms.MessageBoxIcon]::Information
+)
+"#;
+
+const NOTIFY_SCRIPT: &str = r#"
+Add-Type -AssemblyName System.Windows.Forms
+[void][System.Windows.Forms.MessageBox]::Show(
+    $data.message,
+    'Security Key',
+    [System.Windows.Forms.MessageBoxButtons]::OK,
+    [System.Windows.Forms.MessageBoxIcon]::Information
+)
+"#;
+
+/// Input of [`PASSWORD_SCRIPT`].
+#[derive(Serialize)]
+pub(crate) struct PasswordInput<'a> {
+    pub caption: &'a str,
+    pub prompt: &'a str,
+    pub show_save_checkbox: bool,
+    pub retry: bool,
+}
+
+/// Output of [`PASSWORD_SCRIPT`]. The password is base64 encoded UTF-8, so it
+/// arrives exactly as typed.
+#[derive(Deserialize)]
+struct PasswordOutput {
+    status: String,
+    #[serde(default)]
+    password: Secret,
+    #[serde(default)]
+    save: bool,
+}
+
+/// Returns None if the user cancelled. Failures showing the dialog arrive as
+/// script errors instead.
+fn parse_password_output(output: &str) -> Result<Option<PromptResult>> {
+    // The output is not quoted in errors: it may hold the password
+    let malformed = |message: &str| Error::MalformedOutput(message.to_string());
+    let output: PasswordOutput =
+        serde_json::from_str(output).map_err(|_| malformed("Unexpected password dialog output"))?;
+    match output.status.as_str() {
+        "entered" => {
+            let bytes = STANDARD
+                .decode(output.password.expose())
+                .map_err(|_| malformed("Invalid password in dialog output"))?;
+            Ok(Some(PromptResult {
+                password: Secret::from_utf8(bytes),
+                save: output.save,
+            }))
         }
-        anyhow::bail!("PowerShell error: {}", stderr);
+        "cancelled" => Ok(None),
+        status => Err(malformed(&format!("Unexpected password dialog status: {}", status)).into()),
+    }
+}
+
+impl PowerShellPrompter {
+    fn run(&self, script: &str, input: &impl Serialize) -> Result<Secret> {
+        powershell::execute(&self.program, &["-NoProfile"], script, input)
     }
+}
+
+impl Prompter for PowerShellPrompter {
+    fn name(&self) -> &'static str {
+        "powershell"
+    }
+
+    fn is_available(&self) -> bool {
+        dialog::has_desktop() && dialog::is_executable_in_path(&self.program)
+    }
+
+    fn prompt_password(
+        &self,
+        caption: &str,
+        prompt: &str,
+        show_save_checkbox: bool,
+        retry: bool,
+    ) -> Result<Option<PromptResult>> {
+        // Use Windows CredUIPromptForWindowsCredentialsW via PowerShell
+        // This newer API supports both save checkbox and pre-filled username
+        let input = PasswordInput {
+            caption,
+            prompt,
+            show_save_checkbox,
+            retry,
+        };
 
-    let result = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    if result.is_empty() {
-        return Ok(None);
+        parse_password_output(self.run(PASSWORD_SCRIPT, &input)?.expose())
     }
 
-    if let Some(password) = result.strip_prefix("SAVE|") {
-        Ok(Some(PromptResult {
-            password: password.to_string(),
-            save: true,
-        }))
-    } else if let Some(password) = result.strip_prefix("NOSAVE|") {
-        Ok(Some(PromptResult {
-            password: password.to_string(),
-            save: false,
-        }))
-    } else {
-        // Fallback: assume no save
-        Ok(Some(PromptResult {
-            password: result,
-            save: false,
-        }))
+    fn prompt_confirmation(&self, caption: &str, prompt: &str) -> Result<Option<String>> {
+        // Use Windows MessageBox via PowerShell
+        let input = serde_json::json!({ "caption": caption, "prompt": prompt });
+
+        let result = self.run(CONFIRMATION_SCRIPT, &input)?;
+        match result.expose().trim() {
+            "" => Ok(None),
+            result => Ok(Some(result.to_string())),
+        }
+    }
+
+    fn prompt_allow(&self, caption: &str, message: &str) -> Result<bool> {
+        let input = serde_json::json!({ "caption": caption, "message": message });
+
+        Ok(self.run(ALLOW_SCRIPT, &input)?.expose().trim() == "allow")
+    }
+
+    fn notify(&self, message: &str) -> Result<Box<dyn Notification>> {
+        let input = serde_json::json!({ "message": message });
+
+        let child = powershell::spawn(&self.program, &["-NoProfile"], NOTIFY_SCRIPT, &input)?;
+
+        Ok(Box::new(PowerShellNotification { child }))
+    }
+
+    fn show_message(&self, caption: &str, message: &str) -> Result<()> {
+        let input = serde_json::json!({ "caption": caption, "message": message });
+
+        self.run(MESSAGE_SCRIPT, &input)?;
+        Ok(())
     }
 }
 
-pub(crate) fn build_password_script(prompt: &str, show_save_checkbox: bool) -> String {
-    format!(
+/// Defines `CredUI`, showing the password dialog.
+macro_rules! credui_type {
+    () => {
         r#"
 Add-Type -TypeDefinition @"
 using System;
 using System.Runtime.InteropServices;
 using System.Text;
 
-public class CredUI {{
+public class CredUI {
     [DllImport("credui.dll", CharSet = CharSet.Unicode)]
     public static extern int CredUIPromptForWindowsCredentialsW(
         ref CREDUI_INFO pUiInfo,
@@ -119,7 +230,7 @@ public class CredUI {{
         int dwFlags
     );
 
-    [DllImport("credui.dll", CharSet = CharSet.Unicode)]
+    [DllImport("credui.dll", CharSet = CharSet.Unicode, SetLastError = true)]
     public static extern bool CredPackAuthenticationBufferW(
         int dwFlags,
         string pszUserName,
@@ -128,7 +239,7 @@ public class CredUI {{
         ref int pcbPackedCredentials
     );
 
-    [DllImport("credui.dll", CharSet = CharSet.Unicode)]
+    [DllImport("credui.dll", CharSet = CharSet.Unicode, SetLastError = true)]
     public static extern bool CredUnPackAuthenticationBufferW(
         int dwFlags,
         IntPtr pAuthBuffer,
@@ -145,20 +256,22 @@ public class CredUI {{
     public static extern void CoTaskMemFree(IntPtr pv);
 
     [StructLayout(LayoutKind.Sequential, CharSet = CharSet.Unicode)]
-    public struct CREDUI_INFO {{
+    public struct CREDUI_INFO {
         public int cbSize;
         public IntPtr hwndParent;
         public string pszMessageText;
         public string pszCaptionText;
         public IntPtr hbmBanner;
-    }}
+    }
 
     public const int CREDUIWIN_GENERIC = 0x1;
     public const int CREDUIWIN_CHECKBOX = 0x2;
     public const int CREDUIWIN_IN_CRED_ONLY = 0x20;
+    public const int ERROR_INSUFFICIENT_BUFFER = 122;
     public const int ERROR_CANCELLED = 1223;
+    public const int ERROR_LOGON_FAILURE = 1326;
 
-    public static string Prompt(string caption, string message, string username, ref bool save) {{
+    public static string Prompt(string caption, string message, string username, bool showCheckbox, bool retry, ref bool save) {
         CREDUI_INFO info = new CREDUI_INFO();
         info.cbSize = Marshal.SizeOf(info);
         info.pszCaptionText = caption;
@@ -168,24 +281,24 @@ public class CredUI {{
         int inBufferSize = 0;
         CredPackAuthenticationBufferW(0, username, "", IntPtr.Zero, ref inBufferSize);
         IntPtr inBuffer = Marshal.AllocHGlobal(inBufferSize);
-        try {{
-            if (!CredPackAuthenticationBufferW(0, username, "", inBuffer, ref inBufferSize)) {{
-                throw new Exception("CredPackAuthenticationBufferW failed: " + Marshal.GetLastWin32Error());
-            }}
+        try {
+            if (!CredPackAuthenticationBufferW(0, username, "", inBuffer, ref inBufferSize)) {
+                int error = Marshal.GetLastWin32Error();
+                throw new System.ComponentModel.Win32Exception(error, "CredPackAuthenticationBufferW failed: " + error);
+            }
 
             uint authPackage = 0;
             IntPtr outBuffer;
             uint outBufferSize;
 
             int flags = CREDUIWIN_GENERIC | CREDUIWIN_IN_CRED_ONLY;
-            bool showCheckbox = {show_checkbox};
-            if (showCheckbox) {{
+            if (showCheckbox) {
                 flags |= CREDUIWIN_CHECKBOX;
-            }}
+            }
 
             int result = CredUIPromptForWindowsCredentialsW(
                 ref info,
-                0,
+                retry ? ERROR_LOGON_FAILURE : 0,
                 ref authPackage,
                 inBuffer,
                 (uint)inBufferSize,
@@ -195,47 +308,197 @@ public class CredUI {{
                 flags
             );
 
-            if (result == ERROR_CANCELLED) {{
+            if (result == ERROR_CANCELLED) {
                 return null;
-            }} else if (result != 0) {{
-                throw new Exception("CredUIPromptForWindowsCredentialsW error: " + result);
-            }}
-
-            try {{
-                // Unpack the result
-                StringBuilder user = new StringBuilder(256);
-                StringBuilder domain = new StringBuilder(256);
-                StringBuilder pass = new StringBuilder(256);
+            } else if (result != 0) {
+                throw new System.ComponentModel.Win32Exception(result, "CredUIPromptForWindowsCredentialsW error: " + result);
+            }
+
+            try {
+                // Unpack the result, growing the buffers to the sizes the
+                // API asks for
                 int userLen = 256, domainLen = 256, passLen = 256;
+                while (true) {
+                    StringBuilder user = new StringBuilder(userLen);
+                    StringBuilder domain = new StringBuilder(domainLen);
+                    StringBuilder pass = new StringBuilder(passLen);
+                    int userSize = userLen, domainSize = domainLen, passSize = passLen;
 
-                if (!CredUnPackAuthenticationBufferW(0, outBuffer, outBufferSize,
-                    user, ref userLen, domain, ref domainLen, pass, ref passLen)) {{
-                    throw new Exception("CredUnPackAuthenticationBufferW failed: " + Marshal.GetLastWin32Error());
-                }}
+                    if (CredUnPackAuthenticationBufferW(0, outBuffer, outBufferSize,
+                        user, ref userLen, domain, ref domainLen, pass, ref passLen)) {
+                        return pass.ToString();
+                    }
 
-                return pass.ToString();
-            }} finally {{
+                    int error = Marshal.GetLastWin32Error();
+                    if (error != ERROR_INSUFFICIENT_BUFFER ||
+                        (userLen <= userSize && domainLen <= domainSize && passLen <= passSize)) {
+                        throw new System.ComponentModel.Win32Exception(error, "CredUnPackAuthenticationBufferW failed: " + error);
+                    }
+                    userLen = Math.Max(userLen, userSize);
+                    domainLen = Math.Max(domainLen, domainSize);
+                    passLen = Math.Max(passLen, passSize);
+                }
+            } finally {
+                // Clear the packed password before handing the memory back
+                Marshal.Copy(new byte[outBufferSize], 0, outBuffer, (int)outBufferSize);
                 CoTaskMemFree(outBuffer);
-            }}
-        }} finally {{
+            }
+        } finally {
             Marshal.FreeHGlobal(inBuffer);
-        }}
-    }}
-}}
+        }
+    }
+}
 "@
+"#
+    };
+}
+
+pub(crate) use credui_type;
 
+pub(crate) const PASSWORD_SCRIPT: &str = concat!(
+    credui_type!(),
+    r#"
+$result = @{ status = 'cancelled' }
 $save = $false
-$password = [CredUI]::Prompt("SSH Key Passphrase", '{prompt}', "", [ref]$save)
-if ($password -ne $null) {{
-    # Output format: SAVE|password or NOSAVE|password
-    if ($save) {{
-        "SAVE|" + $password
-    }} else {{
-        "NOSAVE|" + $password
-    }}
-}}
-"#,
-        prompt = escape_ps_single_quoted(prompt),
-        show_checkbox = if show_save_checkbox { "true" } else { "false" }
-    )
+$password = [CredUI]::Prompt($data.caption, $data.prompt, "", $data.show_save_checkbox, $data.retry, [ref]$save)
+if ($password -ne $null) {
+    $result.status = 'entered'
+    $result.password = [Convert]::ToBase64String([System.Text.Encoding]::UTF8.GetBytes($password))
+    $result.save = [bool]$save
+}
+$result | ConvertTo-Json -Compress
+"#
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for the Windows dialogs so the scripts run in any
+    /// PowerShell. The dialog answers with its default button and the
+    /// password dialog with the arguments it was given, or cancels when the
+    /// prompt is `cancel`. `Add-Type` is shadowed to skip the real types.
+    const STUBS: &str = r#"
+Microsoft.PowerShell.Utility\Add-Type -TypeDefinition @"
+namespace System.Windows.Forms {
+    public enum MessageBoxButtons { OK, YesNo, YesNoCancel }
+    public enum MessageBoxIcon { Information, Question, Warning }
+    public enum MessageBoxDefaultButton { Button1, Button2 }
+    public enum DialogResult { OK, Yes, No, Cancel }
+
+    public static class MessageBox {
+        public static DialogResult Show(string text, string caption,
+            MessageBoxButtons buttons, MessageBoxIcon icon) {
+            return Show(text, caption, buttons, icon, MessageBoxDefaultButton.Button1);
+        }
+
+        public static DialogResult Show(string text, string caption,
+            MessageBoxButtons buttons, MessageBoxIcon icon, MessageBoxDefaultButton button) {
+            if (buttons == MessageBoxButtons.OK) return DialogResult.OK;
+            return button == MessageBoxDefaultButton.Button2 ? DialogResult.No : DialogResult.Yes;
+        }
+    }
+}
+
+public static class CredUI {
+    public static string Prompt(string caption, string message, string username,
+        bool showCheckbox, bool retry, ref bool save) {
+        if (message == "cancel") return null;
+        save = showCheckbox;
+        return caption + "|" + message + "|" + retry;
+    }
+}
+"@
+function Add-Type {}
+"#;
+
+    /// Runs `script` with the stubbed dialogs.
+    fn run_stubbed(script: &str, input: &impl Serialize) -> Result<Secret> {
+        let program = powershell::installed().expect("PowerShell is not installed");
+        let script = format!("{}{}", STUBS, script);
+        powershell::execute(&program, &["-NoProfile"], &script, input)
+    }
+
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_scripts_are_well_formed() {
+        for script in [
+            CONFIRMATION_SCRIPT,
+            ALLOW_SCRIPT,
+            MESSAGE_SCRIPT,
+            NOTIFY_SCRIPT,
+            PASSWORD_SCRIPT,
+        ] {
+            assert!(powershell::parse(script).errors.is_empty());
+        }
+    }
+
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_allow_script_defaults_to_deny() {
+        let input = serde_json::json!({
+            "caption": "SSH Key Confirmation",
+            "message": "Allow use of key id_rsa?",
+        });
+        let output = run_stubbed(ALLOW_SCRIPT, &input).unwrap();
+        assert_eq!(output.expose(), "");
+    }
+
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_password_script_reports_retry() {
+        let input = PasswordInput {
+            caption: "SSH Key Passphrase",
+            prompt: "Bad passphrase, try again: ",
+            show_save_checkbox: false,
+            retry: true,
+        };
+        let output = run_stubbed(PASSWORD_SCRIPT, &input).unwrap();
+        let result = parse_password_output(output.expose()).unwrap().unwrap();
+        assert!(result.password.expose().ends_with("|True"));
+        assert!(!result.save);
+    }
+
+    #[test]
+    fn test_password_output_as_powershell_writes_it() {
+        // ConvertTo-Json writes hashtable keys in no particular order
+        let result = parse_password_output(
+            r#"{"password":"IHAkc3MgIndvcmQiIA==","save":true,"status":"entered"}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result.password.expose(), r#" p$ss "word" "#);
+        assert!(result.save);
+
+        assert!(
+            parse_password_output(r#"{"status":"cancelled"}"#)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_password_script_output_parses() {
+        let input = PasswordInput {
+            caption: "GPG Passphrase",
+            prompt: "Enter passphrase: ",
+            show_save_checkbox: true,
+            retry: false,
+        };
+        let output = run_stubbed(PASSWORD_SCRIPT, &input).unwrap();
+        let result = parse_password_output(output.expose()).unwrap().unwrap();
+        assert_eq!(
+            result.password.expose(),
+            "GPG Passphrase|Enter passphrase: |False"
+        );
+        assert!(result.save);
+
+        let input = PasswordInput {
+            prompt: "cancel",
+            ..input
+        };
+        let output = run_stubbed(PASSWORD_SCRIPT, &input).unwrap();
+        assert!(parse_password_output(output.expose()).unwrap().is_none());
+    }
 }
@@ -0,0 +1,237 @@
+use crate::dialog::{Notification, PromptResult, Prompter};
+use crate::secret::{self, Secret};
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::AsRawFd;
+
+const TTY: &str = "/dev/tty";
+
+/// Prompts on the controlling terminal, for sessions without a desktop such
+/// as SSH logins or WSL with interop disabled.
+pub struct TtyPrompter;
+
+/// A message printed on the terminal. It stays there, so there is nothing
+/// to dismiss.
+struct TtyNotification;
+
+impl Notification for TtyNotification {
+    fn is_dismissed(&mut self) -> bool {
+        false
+    }
+}
+
+fn open_tty() -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(TTY)
+        .with_context(|| format!("Failed to open {}", TTY))
+}
+
+/// Asks `question` and reads the answer with echo on. None at end of input.
+fn ask(tty: &mut File, question: &str) -> Result<Option<String>> {
+    write!(tty, "{}", question)?;
+    tty.flush()?;
+
+    let mut answer = String::new();
+    if BufReader::new(tty.try_clone()?).read_line(&mut answer)? == 0 {
+        writeln!(tty)?;
+        return Ok(None);
+    }
+    Ok(Some(answer.trim().to_string()))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer, "y" | "Y" | "yes" | "Yes")
+}
+
+/// Puts the terminal into a mode without echo and without signal keys while
+/// alive, so that Ctrl-C is read as a key and the previous mode is always
+/// restored.
+struct RawMode<'a> {
+    tty: &'a File,
+    saved: libc::termios,
+}
+
+impl<'a> RawMode<'a> {
+    fn enter(tty: &'a File) -> io::Result<RawMode<'a>> {
+        let fd = tty.as_raw_fd();
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawMode { tty, saved })
+    }
+}
+
+impl Drop for RawMode<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSAFLUSH, &self.saved) };
+    }
+}
+
+/// Reads a line of keys as typed in raw mode, with backspace and Ctrl-U
+/// editing. Returns None on Ctrl-C, or Ctrl-D on an empty line.
+fn read_secret(mut input: impl Read) -> io::Result<Option<Secret>> {
+    let mut secret = Secret::default();
+    // Read a byte at a time: a buffered reader would keep a copy of the keys
+    let mut key = [0u8; 1];
+    // Bytes of a character that is not complete yet
+    let mut pending = [0u8; 4];
+    let mut pending_len = 0;
+
+    let mut result = Ok(None);
+    loop {
+        match input.read(&mut key) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+        let byte = key[0];
+        match byte {
+            b'\r' | b'\n' => {
+                result = Ok(Some(secret));
+                break;
+            }
+            0x03 => break,
+            0x04 => {
+                if secret.expose().is_empty() {
+                    break;
+                }
+            }
+            0x7f | 0x08 => {
+                secret.pop();
+            }
+            0x15 => while secret.pop().is_some() {},
+            _ => {
+                pending[pending_len] = byte;
+                pending_len += 1;
+                match std::str::from_utf8(&pending[..pending_len]) {
+                    Ok(text) => {
+                        text.chars().for_each(|c| secret.push(c));
+                        pending_len = 0;
+                    }
+                    Err(e) if e.error_len().is_some() || pending_len == pending.len() => {
+                        pending_len = 0;
+                    }
+                    // Wait for the rest of the character
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    secret::wipe(&mut key);
+    secret::wipe(&mut pending);
+    result
+}
+
+impl Prompter for TtyPrompter {
+    fn name(&self) -> &'static str {
+        "tty"
+    }
+
+    fn is_available(&self) -> bool {
+        open_tty().is_ok()
+    }
+
+    fn prompt_password(
+        &self,
+        _caption: &str,
+        prompt: &str,
+        show_save_checkbox: bool,
+        _retry: bool,
+    ) -> Result<Option<PromptResult>> {
+        // ssh already says in the prompt when the last passphrase was wrong
+        let mut tty = open_tty()?;
+        write!(tty, "{}", prompt)?;
+        tty.flush()?;
+
+        let password = {
+            let _raw = RawMode::enter(&tty).context("Failed to disable terminal echo")?;
+            read_secret(&tty)?
+        };
+        writeln!(tty)?;
+        let Some(password) = password else {
+            return Ok(None);
+        };
+
+        let save = show_save_checkbox
+            && ask(&mut tty, "Save passphrase to the credential store? [y/N] ")?
+                .is_some_and(|answer| is_yes(&answer));
+        Ok(Some(PromptResult { password, save }))
+    }
+
+    fn prompt_text(&self, _caption: &str, prompt: &str) -> Result<Option<Secret>> {
+        let mut tty = open_tty()?;
+        Ok(ask(&mut tty, prompt)?.map(Secret::from))
+    }
+
+    fn prompt_confirmation(&self, _caption: &str, prompt: &str) -> Result<Option<String>> {
+        // ssh asks for yes, no or the host key fingerprint
+        let mut tty = open_tty()?;
+        Ok(ask(&mut tty, prompt)?.filter(|answer| !answer.is_empty()))
+    }
+
+    fn prompt_allow(&self, _caption: &str, message: &str) -> Result<bool> {
+        let mut tty = open_tty()?;
+        let question = format!("{} [y/N] ", message.trim_end());
+        Ok(ask(&mut tty, &question)?.is_some_and(|answer| is_yes(&answer)))
+    }
+
+    fn notify(&self, message: &str) -> Result<Box<dyn Notification>> {
+        let mut tty = open_tty()?;
+        writeln!(tty, "{}", message)?;
+        Ok(Box::new(TtyNotification))
+    }
+
+    fn show_message(&self, _caption: &str, message: &str) -> Result<()> {
+        let mut tty = open_tty()?;
+        writeln!(tty, "{}", message.trim_end())?;
+        ask(&mut tty, "Press Enter to continue ")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(keys: &[u8]) -> Option<String> {
+        read_secret(keys)
+            .unwrap()
+            .map(|secret| secret.expose().to_string())
+    }
+
+    #[test]
+    fn test_line_editing() {
+        assert_eq!(read(b"hunter2\r").as_deref(), Some("hunter2"));
+        assert_eq!(read(b"hunx\x7fter2\n").as_deref(), Some("hunter2"));
+        assert_eq!(read(b"wrong\x15right\r").as_deref(), Some("right"));
+        assert_eq!(read(b" spaced \r").as_deref(), Some(" spaced "));
+        assert_eq!(
+            read("pässwörd 🔑\r".as_bytes()).as_deref(),
+            Some("pässwörd 🔑")
+        );
+    }
+
+    #[test]
+    fn test_cancel_keys() {
+        assert_eq!(read(b"hunter2\x03"), None);
+        assert_eq!(read(b"\x04"), None);
+        assert_eq!(read(b"hunter2"), None);
+        assert_eq!(read(b"a\x04b\r").as_deref(), Some("ab"));
+    }
+}
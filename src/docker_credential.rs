@@ -0,0 +1,187 @@
+//! `docker-credential-winaskpass`: the protocol of docker's credential
+//! helpers, keeping registry logins out of `~/.docker/config.json`.
+
+use crate::credential::{CredentialStore, Metadata, Target};
+use crate::error::Error;
+use crate::secret::Secret;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+/// Registry logins are stored apart from SSH keys under this prefix.
+const TARGET_PREFIX: &str = "docker:";
+
+/// What docker matches in its error output to tell a missing login apart
+/// from a failure.
+const NOT_FOUND: &str = "credentials not found in native keychain";
+
+#[derive(Serialize, Deserialize)]
+struct Credentials {
+    #[serde(rename = "ServerURL")]
+    server_url: String,
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: Secret,
+}
+
+fn target(server_url: &str, username: Option<String>) -> Target {
+    Target::named(
+        format!("{}{}", TARGET_PREFIX, server_url),
+        Metadata {
+            username,
+            ..Default::default()
+        },
+    )
+}
+
+/// Reads the server URL that `get` and `erase` receive on stdin.
+fn read_server_url(mut input: impl Read) -> Result<String> {
+    let mut server_url = String::new();
+    input.read_to_string(&mut server_url)?;
+    let server_url = server_url.trim();
+    if server_url.is_empty() {
+        anyhow::bail!("no credentials server URL");
+    }
+    Ok(server_url.to_string())
+}
+
+/// Runs `action`, reading its input from `input` and writing the reply to
+/// `output`.
+pub fn handle(
+    store: &dyn CredentialStore,
+    action: Option<&str>,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<()> {
+    match action {
+        Some("store") => {
+            let mut json = Vec::new();
+            input.read_to_end(&mut json)?;
+            let json = Secret::from_utf8(json);
+            let credentials: Credentials =
+                serde_json::from_str(json.expose()).context("Invalid credentials")?;
+            target(&credentials.server_url, Some(credentials.username))
+                .store(store, credentials.secret.expose())
+                .context("Failed to save credentials")
+        }
+        Some("get") => {
+            let server_url = read_server_url(input)?;
+            let credential = target(&server_url, None)
+                .get_credential(store)?
+                .ok_or_else(|| Error::NotFound(NOT_FOUND.to_string()))?;
+            let credentials = Credentials {
+                server_url,
+                username: credential.metadata.username.unwrap_or_default(),
+                secret: credential.secret,
+            };
+            serde_json::to_writer(&mut output, &credentials)?;
+            writeln!(output)?;
+            Ok(())
+        }
+        Some("erase") => {
+            let server_url = read_server_url(input)?;
+            if store.delete(&target(&server_url, None).name)? {
+                Ok(())
+            } else {
+                Err(Error::NotFound(NOT_FOUND.to_string()).into())
+            }
+        }
+        Some("list") => {
+            let logins: BTreeMap<String, String> = store
+                .list()?
+                .into_iter()
+                .filter_map(|entry| {
+                    let server_url = entry.name.strip_prefix(TARGET_PREFIX)?.to_string();
+                    Some((server_url, entry.metadata.username.unwrap_or_default()))
+                })
+                .collect();
+            serde_json::to_writer(&mut output, &logins)?;
+            writeln!(output)?;
+            Ok(())
+        }
+        Some("version") => {
+            writeln!(output, "winaskpass {}", env!("CARGO_PKG_VERSION"))?;
+            Ok(())
+        }
+        Some(action) => anyhow::bail!("Unknown credential action `{}`", action),
+        None => anyhow::bail!("Usage: docker-credential-winaskpass store|get|erase|list|version"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::memory::MemoryStore;
+
+    fn run(store: &MemoryStore, action: &str, input: &str) -> Result<String> {
+        let mut output = Vec::new();
+        handle(store, Some(action), input.as_bytes(), &mut output)?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_store_get_and_list() {
+        let store = MemoryStore::default();
+        store
+            .store(
+                "/home/user/.ssh/id_ed25519",
+                "hunter2",
+                &Metadata::default(),
+            )
+            .unwrap();
+        run(
+            &store,
+            "store",
+            r#"{"ServerURL":"https://index.docker.io/v1/","Username":"whale","Secret":"s3cr\"t"}"#,
+        )
+        .unwrap();
+
+        let credential = store
+            .get("docker:https://index.docker.io/v1/")
+            .unwrap()
+            .unwrap();
+        assert_eq!(credential.secret.expose(), "s3cr\"t");
+
+        let output = run(&store, "get", "https://index.docker.io/v1/\n").unwrap();
+        let got: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            got,
+            serde_json::json!({
+                "ServerURL": "https://index.docker.io/v1/",
+                "Username": "whale",
+                "Secret": "s3cr\"t",
+            })
+        );
+
+        // SSH passphrases are not registry logins
+        assert_eq!(
+            run(&store, "list", "").unwrap().trim(),
+            r#"{"https://index.docker.io/v1/":"whale"}"#
+        );
+    }
+
+    #[test]
+    fn test_missing_login_reported_as_docker_expects() {
+        let store = MemoryStore::default();
+        for action in ["get", "erase"] {
+            let error = run(&store, action, "ghcr.io").unwrap_err();
+            assert_eq!(error.to_string(), NOT_FOUND);
+            assert_eq!(crate::error::exit_code(&error), 4);
+        }
+    }
+
+    #[test]
+    fn test_erase() {
+        let store = MemoryStore::default();
+        run(
+            &store,
+            "store",
+            r#"{"ServerURL":"ghcr.io","Username":"octocat","Secret":"token"}"#,
+        )
+        .unwrap();
+        run(&store, "erase", "ghcr.io\n").unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+
+/// Exit code of errors that are not an [`Error`], such as usage mistakes or
+/// a failing PowerShell script.
+pub const FAILURE: u8 = 2;
+
+/// Failures that wrapper scripts can tell apart by the exit code.
+#[derive(Debug)]
+pub enum Error {
+    /// The user cancelled the dialog or denied the request
+    Cancelled,
+    /// No credential store or dialog backend can be used, e.g. because
+    /// `powershell.exe` cannot be started
+    Unavailable(String),
+    /// The credential asked for is not stored
+    NotFound(String),
+    /// Credential Manager refused the operation
+    AccessDenied(String),
+    /// A backend answered with something that could not be understood
+    MalformedOutput(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Cancelled => 1,
+            Error::Unavailable(_) => 3,
+            Error::NotFound(_) => 4,
+            Error::AccessDenied(_) => 5,
+            Error::MalformedOutput(_) => 6,
+        }
+    }
+
+    /// The error for a Windows error code, if it has a typed equivalent.
+    pub fn from_win32(code: u32, message: String) -> Option<Error> {
+        match code {
+            5 => Some(Error::AccessDenied(message)),
+            1168 => Some(Error::NotFound(message)),
+            1223 => Some(Error::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Cancelled => write!(f, "Cancelled by the user"),
+            Error::Unavailable(message)
+            | Error::NotFound(message)
+            | Error::AccessDenied(message)
+            | Error::MalformedOutput(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The exit code for `error`: that of the first [`Error`] in its chain, or
+/// [`FAILURE`].
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Error>())
+        .map_or(FAILURE, Error::exit_code)
+}
+
+/// `error` and its causes on a single line, for stderr.
+pub fn message(error: &anyhow::Error) -> String {
+    format!("{:#}", error)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_code_found_behind_context() {
+        let error = Err::<(), _>(Error::AccessDenied("CredWriteW failed: 5".to_string()))
+            .context("Failed to save credential")
+            .unwrap_err();
+        assert_eq!(exit_code(&error), 5);
+        assert_eq!(exit_code(&anyhow::anyhow!("PowerShell error")), FAILURE);
+        assert_eq!(exit_code(&Error::Cancelled.into()), 1);
+    }
+
+    #[test]
+    fn test_message_is_one_line() {
+        let error = anyhow::anyhow!("PowerShell error: At line:1\r\n  + CategoryInfo\n")
+            .context("Failed to read credential");
+        assert_eq!(
+            message(&error),
+            "Failed to read credential: PowerShell error: At line:1 + CategoryInfo"
+        );
+    }
+
+    #[test]
+    fn test_win32_codes() {
+        assert!(matches!(
+            Error::from_win32(5, String::new()),
+            Some(Error::AccessDenied(_))
+        ));
+        assert!(matches!(
+            Error::from_win32(1223, String::new()),
+            Some(Error::Cancelled)
+        ));
+        assert!(Error::from_win32(87, String::new()).is_none());
+    }
+}
@@ -0,0 +1,232 @@
+//! `winaskpass credential get|store|erase`: git's credential helper
+//! protocol, keeping the user name and password of a remote in one entry.
+
+use crate::credential::{CredentialStore, Metadata, Target};
+use crate::secret::Secret;
+use anyhow::Result;
+use std::io::{BufRead, Write};
+
+/// The attributes git describes a credential with.
+#[derive(Default)]
+struct Request {
+    protocol: Option<String>,
+    host: Option<String>,
+    path: Option<String>,
+    username: Option<String>,
+    password: Option<Secret>,
+}
+
+impl Request {
+    /// Reads `key=value` lines up to a blank line or the end of input.
+    fn read(mut input: impl BufRead) -> Result<Request> {
+        let mut request = Request::default();
+        loop {
+            // The line may hold the password
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = Secret::from(line);
+            let line = line.expose().trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.to_string();
+            match key {
+                "protocol" => request.protocol = Some(value),
+                "host" => request.host = Some(value),
+                "path" => request.path = Some(value),
+                "username" => request.username = Some(value),
+                "password" => request.password = Some(Secret::from(value)),
+                "url" => request.set_url(&value),
+                // capability[], wwwauth[], password_expiry_utc and the like
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+
+    /// Splits `protocol://[user@]host[/path]` into its attributes.
+    fn set_url(&mut self, url: &str) {
+        let Some((protocol, rest)) = url.split_once("://") else {
+            return;
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let host = match authority.rsplit_once('@') {
+            Some((user, host)) => {
+                // Anything after a colon would be a password, which git
+                // passes separately
+                let user = user.split(':').next().unwrap_or(user);
+                self.username = Some(user.to_string());
+                host
+            }
+            None => authority,
+        };
+        self.protocol = Some(protocol.to_string());
+        self.host = Some(host.to_string());
+        self.path = Some(path.to_string()).filter(|path| !path.is_empty());
+    }
+
+    /// The credential for the remote, None if git did not say which one.
+    /// The path only takes part when git sends it, i.e. with
+    /// `credential.useHttpPath`.
+    fn target(&self) -> Option<Target> {
+        let mut name = format!("git:{}://{}", self.protocol.as_ref()?, self.host.as_ref()?);
+        if let Some(path) = &self.path {
+            name.push('/');
+            name.push_str(path.trim_start_matches('/'));
+        }
+        Some(Target::named(
+            name,
+            Metadata {
+                username: self.username.clone(),
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// Whether a stored credential belongs to the user git asked about.
+    fn matches_username(&self, stored: Option<&str>) -> bool {
+        match (self.username.as_deref(), stored) {
+            (Some(asked), Some(stored)) => asked == stored,
+            _ => true,
+        }
+    }
+}
+
+/// Runs `action`. Unknown actions are ignored, as git expects of helpers.
+pub fn handle(
+    store: &dyn CredentialStore,
+    action: Option<&str>,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> Result<()> {
+    let request = Request::read(input)?;
+    let Some(target) = request.target() else {
+        return Ok(());
+    };
+
+    match action {
+        Some("get") => {
+            let Some(credential) = target.get_credential(store)? else {
+                return Ok(());
+            };
+            if !request.matches_username(credential.metadata.username.as_deref()) {
+                return Ok(());
+            }
+            if let Some(username) = &credential.metadata.username {
+                writeln!(output, "username={}", username)?;
+            }
+            writeln!(output, "password={}", credential.secret.expose())?;
+            output.flush()?;
+        }
+        Some("store") => {
+            // git only asks to store credentials that worked, but check
+            if let Some(password) = &request.password
+                && request.username.is_some()
+            {
+                target.store(store, password.expose())?;
+            }
+        }
+        Some("erase") => {
+            if let Some(credential) = store.get(&target.name)?
+                && request.matches_username(credential.metadata.username.as_deref())
+            {
+                target.delete(store)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::memory::MemoryStore;
+
+    fn run(store: &MemoryStore, action: &str, input: &str) -> String {
+        let mut output = Vec::new();
+        handle(store, Some(action), input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    const REMOTE: &str = "protocol=https\nhost=github.com\n";
+
+    #[test]
+    fn test_store_then_get_returns_username_and_password() {
+        let store = MemoryStore::default();
+        run(
+            &store,
+            "store",
+            &format!("{}username=octocat\npassword=p=ss word\n\n", REMOTE),
+        );
+
+        let entries = store.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "git:https://github.com");
+        assert_eq!(entries[0].metadata.username.as_deref(), Some("octocat"));
+
+        assert_eq!(
+            run(&store, "get", &format!("{}\n", REMOTE)),
+            "username=octocat\npassword=p=ss word\n"
+        );
+        assert_eq!(store.list().unwrap()[0].metadata.use_hours, 1);
+    }
+
+    #[test]
+    fn test_get_honors_username_and_path() {
+        let store = MemoryStore::default();
+        run(
+            &store,
+            "store",
+            "url=https://octocat@example.com/org/repo.git\npassword=secret\n",
+        );
+        assert_eq!(
+            store.list().unwrap()[0].name,
+            "git:https://example.com/org/repo.git"
+        );
+
+        let with_path = "protocol=https\nhost=example.com\npath=org/repo.git\n";
+        assert_eq!(
+            run(&store, "get", &format!("{}username=octocat\n", with_path)),
+            "username=octocat\npassword=secret\n"
+        );
+        assert_eq!(
+            run(&store, "get", &format!("{}username=other\n", with_path)),
+            ""
+        );
+        assert_eq!(run(&store, "get", "protocol=https\nhost=example.com\n"), "");
+    }
+
+    #[test]
+    fn test_erase_only_matching_user() {
+        let store = MemoryStore::default();
+        run(
+            &store,
+            "store",
+            &format!("{}username=octocat\npassword=secret\n", REMOTE),
+        );
+
+        run(&store, "erase", &format!("{}username=other\n", REMOTE));
+        assert_eq!(store.list().unwrap().len(), 1);
+        run(
+            &store,
+            "erase",
+            &format!("{}username=octocat\npassword=secret\n", REMOTE),
+        );
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_incomplete_requests_ignored() {
+        let store = MemoryStore::default();
+        run(&store, "store", "protocol=https\npassword=secret\n");
+        run(&store, "store", &format!("{}password=secret\n", REMOTE));
+        run(&store, "frobnicate", REMOTE);
+        assert!(store.list().unwrap().is_empty());
+    }
+}
@@ -1,133 +1,472 @@
+mod cargo_credential;
+mod config;
+mod credential;
+mod dialog;
+mod docker_credential;
+mod error;
+
 #[cfg(feature = "native")]
 mod credential_native;
-#[cfg(feature = "native")]
-use credential_native as credential;
 
 #[cfg(feature = "powershell")]
 mod credential_powershell;
-#[cfg(feature = "powershell")]
-use credential_powershell as credential;
 
 #[cfg(feature = "native")]
 mod dialog_native;
-#[cfg(feature = "native")]
-use dialog_native as dialog;
 
 #[cfg(feature = "powershell")]
 mod dialog_powershell;
+
+#[cfg(unix)]
+mod dialog_tty;
+
+mod dialog_pinentry;
+
+#[cfg(feature = "powershell")]
+mod askpass_powershell;
+
 #[cfg(feature = "powershell")]
-use dialog_powershell as dialog;
+mod powershell;
+
+#[cfg(all(feature = "powershell", unix))]
+mod powershell_helper;
+
+mod git_credential;
+mod key;
+mod pinentry_server;
+mod prompt;
+mod secret;
+#[cfg(target_os = "linux")]
+mod systemd_agent;
 
 use anyhow::Result;
+use credential::{CredentialEntry, CredentialStore, Metadata, Target};
+use dialog::Prompter;
+use error::Error;
+use prompt::PromptKind;
+use secret::Secret;
+use serde::Serialize;
+use signal_hook::consts::SIGTERM;
 use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
-fn is_host_authenticity_prompt(prompt: &str) -> bool {
-    // SSH sends prompts like:
-    // "The authenticity of host 'foo (1.2.3.4)' can't be established..."
-    // "Are you sure you want to continue connecting (yes/no/[fingerprint])?"
-    prompt.contains("authenticity of host") || prompt.contains("continue connecting (yes/no")
+fn handle_askpass(
+    store: &dyn CredentialStore,
+    prompter: &dyn Prompter,
+    prompt: &str,
+) -> Result<()> {
+    match PromptKind::parse(prompt) {
+        // Handle SSH host authenticity prompts separately
+        // These require user confirmation, not credential retrieval
+        kind @ PromptKind::HostAuthenticity { .. } => {
+            match prompter.prompt_confirmation(kind.caption(), prompt)? {
+                Some(answer) => {
+                    print!("{}", answer);
+                    Ok(())
+                }
+                None => Err(Error::Cancelled.into()),
+            }
+        }
+        PromptKind::ConfirmKeyUse { .. } => handle_confirm(prompter, prompt),
+        PromptKind::UserPresence { .. } => handle_notify(prompter, prompt),
+        kind => match answer_password(store, prompter, prompt, &kind)? {
+            Some(password) => {
+                print!("{}", password.expose());
+                Ok(())
+            }
+            None => Err(Error::Cancelled.into()),
+        },
+    }
 }
 
-fn extract_key_path(prompt: &str) -> Option<&str> {
-    // ssh-add sends prompts like:
-    // "Enter passphrase for /home/user/.ssh/id_rsa: "
-    // "Enter passphrase for key '/home/user/.ssh/id_rsa': "
-    // We need to extract the key path
+/// Answers a password prompt from the cache, or from the dialog and then
+/// caches the answer if the user asked to. Returns None if the user cancelled.
+fn answer_password(
+    store: &dyn CredentialStore,
+    prompter: &dyn Prompter,
+    prompt: &str,
+    kind: &PromptKind,
+) -> Result<Option<Secret>> {
+    let target = Target::for_prompt(kind);
+    let retry = matches!(kind, PromptKind::BadPassphrase { .. });
 
-    let prompt = prompt.trim();
+    // Both backends in PowerShell: look up and prompt in one run
+    #[cfg(feature = "powershell")]
+    if let Some(target) = &target
+        && store.name() == "powershell"
+        && prompter.name() == "powershell"
+    {
+        return askpass_powershell::PowerShellAskpass::default().answer(
+            store,
+            &askpass_powershell::AskpassRequest {
+                target,
+                caption: kind.caption(),
+                prompt,
+                retry,
+            },
+        );
+    }
 
-    // Try pattern with quotes first
-    if let Some(start) = prompt.find("'")
-        && let Some(end) = prompt.rfind("'")
-        && end > start
+    if retry {
+        // The cached passphrase was rejected: drop it so it is not
+        // offered again
+        if let Some(target) = &target
+            && let Err(e) = target.delete(store)
+        {
+            eprintln!("Warning: Failed to delete stale credential: {}", e);
+        }
+    } else if let Some(target) = &target {
+        // Try to get cached credential, and prompt if it cannot be read
+        match target.get(store) {
+            Ok(Some(password)) => return Ok(Some(password)),
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: Failed to read cached credential: {}", e),
+        }
+    }
+
+    // Prompt user for password
+    // Only show save checkbox if we have a target to save against
+    let Some(result) = prompter.prompt_password(kind.caption(), prompt, target.is_some(), retry)?
+    else {
+        return Ok(None);
+    };
+
+    if result.save
+        && let Some(target) = &target
+        && let Err(e) = target.store(store, result.password.expose())
     {
-        return Some(&prompt[start + 1..end]);
+        eprintln!("Warning: Failed to save credential: {}", e);
+    }
+    Ok(Some(result.password))
+}
+
+/// SSH_ASKPASS_PROMPT=confirm: ssh-agent asks whether a key added with
+/// `ssh-add -c` may be used. Only the exit status matters, nothing is printed
+/// and the credential store is never touched.
+fn handle_confirm(prompter: &dyn Prompter, prompt: &str) -> Result<()> {
+    let kind = PromptKind::parse(prompt);
+    let message = match kind {
+        PromptKind::ConfirmKeyUse {
+            comment,
+            fingerprint,
+        } => match fingerprint {
+            Some(fingerprint) => format!(
+                "Allow use of key {}?\n\nFingerprint: {}",
+                comment, fingerprint
+            ),
+            None => format!("Allow use of key {}?", comment),
+        },
+        _ => prompt.trim().to_string(),
+    };
+
+    if prompter.prompt_allow(kind.caption(), &message)? {
+        Ok(())
+    } else {
+        Err(Error::Cancelled.into())
+    }
+}
+
+/// SSH_ASKPASS_PROMPT=none: ssh shows a notice while waiting for a security
+/// key to be touched and kills us once it has been.
+fn handle_notify(prompter: &dyn Prompter, prompt: &str) -> Result<()> {
+    let message = match PromptKind::parse(prompt) {
+        PromptKind::UserPresence {
+            key_type,
+            fingerprint,
+        } => format!(
+            "Touch your security key to confirm user presence.\n\n{} key {}",
+            key_type, fingerprint
+        ),
+        _ => prompt.trim().to_string(),
+    };
+
+    let terminated = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGTERM, Arc::clone(&terminated))?;
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&terminated))?;
+
+    let mut notification = prompter.notify(&message)?;
+    while !terminated.load(Ordering::Relaxed) && !notification.is_dismissed() {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // Dropping the notification closes it
+    Ok(())
+}
+
+/// An entry of `--list --json`, labelled with what it is for.
+#[derive(Serialize)]
+struct Listed<'a> {
+    kind: &'static str,
+    #[serde(flatten)]
+    entry: &'a CredentialEntry,
+}
+
+/// Lists the credentials of every kind: SSH passphrases and those stored by
+/// the helper modes.
+fn handle_list(store: &dyn CredentialStore, args: &[String]) -> Result<()> {
+    let entries: Vec<CredentialEntry> = store
+        .list()?
+        .into_iter()
+        .map(credential::with_configured_ttl)
+        .collect();
+    if args.iter().any(|arg| arg == "--json") {
+        let listed: Vec<Listed> = entries
+            .iter()
+            .map(|entry| Listed {
+                kind: entry.kind(),
+                entry,
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+        return Ok(());
     }
 
-    // Try pattern without quotes: "Enter passphrase for /path/to/key:"
-    if let Some(idx) = prompt.find("for ") {
-        let rest = &prompt[idx + 4..];
-        // Remove trailing colon and whitespace
-        let path = rest.trim_end_matches(':').trim();
-        if !path.is_empty() {
-            return Some(path);
+    if entries.is_empty() {
+        println!("No credentials stored.");
+    } else {
+        print!("{}", format_table(&entries));
+    }
+    Ok(())
+}
+
+/// Lays out the stored credentials as a table, one per line.
+fn format_table(entries: &[CredentialEntry]) -> String {
+    let header = [
+        "NAME",
+        "KIND",
+        "TYPE",
+        "CREATED",
+        "LAST RECORDED USE",
+        "EXPIRES",
+        "USE HOURS",
+        "PROGRAM",
+    ];
+    let rows: Vec<[String; 8]> = entries
+        .iter()
+        .map(|entry| {
+            let metadata = &entry.metadata;
+            let name = match &metadata.key_path {
+                Some(path) if *path != entry.name => format!("{} ({})", entry.name, path),
+                _ => entry.name.clone(),
+            };
+            [
+                name,
+                entry.kind().to_string(),
+                metadata.key_type.clone().unwrap_or_else(|| "-".to_string()),
+                metadata.created.map_or("-".to_string(), format_time),
+                metadata
+                    .last_recorded_use
+                    .map_or("-".to_string(), format_time),
+                metadata.expires().map_or("-".to_string(), format_time),
+                metadata.use_hours.to_string(),
+                metadata.program.clone().unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(|title| title.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
         }
     }
 
-    None
+    let mut table = String::new();
+    for row in std::iter::once(header.map(str::to_string)).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
 }
 
-fn handle_askpass(prompt: &str) -> Result<()> {
-    // Handle SSH host authenticity prompts separately
-    // These require user confirmation, not credential retrieval
-    if is_host_authenticity_prompt(prompt) {
-        match dialog::prompt_confirmation(prompt)? {
-            Some(answer) => {
-                print!("{}", answer);
-                Ok(())
-            }
-            None => {
-                // User cancelled
-                std::process::exit(1);
-            }
+/// Formats a Unix time as `YYYY-MM-DD HH:MM` in UTC.
+fn format_time(time: u64) -> String {
+    let days = (time / 86400) as i64;
+    let seconds = time % 86400;
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// Re-keys SSH key passphrases stored by key path under the key's
+/// fingerprint. Only done in fingerprint mode.
+fn handle_migrate(store: &dyn CredentialStore) -> Result<()> {
+    // Lookups would not find re-keyed credentials
+    if !key::fingerprint_mode() {
+        anyhow::bail!("Set `key_id = fingerprint` before migrating credentials");
+    }
+
+    let mut migrated = 0;
+    for entry in store.list()? {
+        if entry.kind() != "ssh" || key::is_fingerprint(&entry.name) {
+            continue;
         }
-    } else {
-        let key_path = extract_key_path(prompt);
 
-        // Try to get cached credential
-        if let Some(path) = key_path
-            && let Some(password) = credential::get_credential(path)?
-        {
-            print!("{}", password);
-            return Ok(());
+        let Some(public_key) = key::public_key(Path::new(&key::local_key_path(&entry.name))) else {
+            continue;
+        };
+
+        if let Some(credential) = store.get(&entry.name)? {
+            let metadata = Metadata {
+                key_path: Some(entry.name.clone()),
+                key_type: Some(public_key.key_type),
+                fingerprint: Some(public_key.fingerprint.clone()),
+                ..credential.metadata
+            };
+            store.store(
+                &public_key.fingerprint,
+                credential.secret.expose(),
+                &metadata,
+            )?;
+            store.delete(&entry.name)?;
+            println!("  {} -> {}", entry.name, public_key.fingerprint);
+            migrated += 1;
         }
+    }
+    println!("Migrated {} credential(s).", migrated);
+    Ok(())
+}
 
-        // Prompt user for password
-        // Only show save checkbox if we have a key path to save against
-        match dialog::prompt_password(prompt, key_path.is_some())? {
-            Some(result) => {
-                if result.save
-                    && let Some(path) = key_path
-                    && let Err(e) = credential::store_credential(path, &result.password)
-                {
-                    eprintln!("Warning: Failed to save credential: {}", e);
-                }
-                print!("{}", result.password);
-                Ok(())
-            }
-            None => {
-                // User cancelled
-                std::process::exit(1);
-            }
+/// Deletes every stored credential whose TTL has run out.
+fn handle_prune(store: &dyn CredentialStore) -> Result<()> {
+    let mut pruned = 0;
+    for entry in store
+        .list()?
+        .into_iter()
+        .map(credential::with_configured_ttl)
+    {
+        if entry.metadata.is_expired() && store.delete(&entry.name)? {
+            println!("  {}", entry.name);
+            pruned += 1;
         }
     }
+    println!("Pruned {} expired credential(s).", pruned);
+    Ok(())
 }
 
-fn handle_list() -> Result<()> {
-    let keys = credential::list_credentials()?;
-    if keys.is_empty() {
-        println!("No SSH credentials stored.");
-    } else {
-        println!("Stored SSH credentials:");
-        for key in keys {
-            println!("  {}", key);
+/// Deletes the stored credentials named by `args`: key paths (spelled any
+/// way the key could be found), glob patterns over the stored names, or
+/// `--all`. Asks before deleting unless `--yes` is given.
+fn handle_forget(store: &dyn CredentialStore, args: &[String]) -> Result<()> {
+    let mut yes = false;
+    let mut all = false;
+    let mut patterns = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--yes" | "-y" => yes = true,
+            "--all" | "-a" => all = true,
+            _ => patterns.push(arg.as_str()),
         }
     }
+
+    if !all && patterns.is_empty() {
+        anyhow::bail!("Usage: winaskpass forget [--yes] <key|pattern>... | --all");
+    }
+
+    let entries = store.list()?;
+    let selected: Vec<&CredentialEntry> = entries
+        .iter()
+        .filter(|entry| {
+            all || patterns
+                .iter()
+                .any(|pattern| credential::matches_pattern(entry, pattern))
+        })
+        .collect();
+
+    if selected.is_empty() {
+        if all {
+            println!("No credentials stored.");
+            return Ok(());
+        }
+        return Err(Error::NotFound("No matching credentials stored".to_string()).into());
+    }
+
+    // Labelled, since patterns and --all reach helper credentials as well
+    println!("Credentials to delete:");
+    for entry in &selected {
+        println!("  {} ({})", entry.name, entry.kind());
+    }
+
+    if !yes && !confirm_on_terminal("Delete these credentials?")? {
+        println!("Nothing deleted.");
+        return Ok(());
+    }
+
+    for entry in selected {
+        store.delete(&entry.name)?;
+    }
     Ok(())
 }
 
+/// Asks a yes/no question on the terminal, defaulting to no.
+fn confirm_on_terminal(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
 fn print_help() {
-    #[cfg(feature = "native")]
-    let binary_name = "winaskpass.exe";
-    #[cfg(feature = "powershell")]
-    let binary_name = "winaskpass";
+    let binary_name = if cfg!(feature = "native") {
+        "winaskpass.exe"
+    } else {
+        "winaskpass"
+    };
 
     eprintln!(
         r#"winaskpass - ssh-add helper for WSL with Windows Credential Manager
 
 USAGE:
     winaskpass <prompt>           SSH_ASKPASS mode: respond to ssh-add prompt
-    winaskpass --list             List stored SSH credentials
+    winaskpass --list [--json]    List stored credentials of every kind and
+                                  their metadata
+    winaskpass --migrate          Re-key path-based credentials by key fingerprint
+    winaskpass forget [--yes] <key|pattern>...
+                                  Delete stored credentials; patterns may use * and ?
+    winaskpass forget [--yes] --all
+    winaskpass clear [--yes]      Delete all stored credentials, also those
+                                  of git, docker, cargo, gpg and systemd
+    winaskpass prune              Delete expired credentials
+    winaskpass credential get|store|erase
+                                  Git credential helper, see gitcredentials(7)
+    winaskpass cargo-credential   Cargo credential provider
+    winaskpass docker-credential store|get|erase|list
+                                  Docker credential helper, also when run
+                                  through a link named docker-credential-*
+    winaskpass systemd-agent      Answer systemd-ask-password requests (as root)
+    winaskpass --pinentry         Act as pinentry for gpg-agent, also when run
+                                  through a link named pinentry-*
     winaskpass --help             Show this help
 
 SETUP:
@@ -139,24 +478,131 @@ SETUP:
         ssh-add </dev/null
 
     The passphrase will be cached in Windows Credential Manager.
+
+    Settings are read from WINASKPASS_<NAME> environment variables or
+    from `name = value` lines in ~/.config/winaskpass/config:
+        store = native | powershell
+        dialog = native,powershell,tty,pinentry   (tried in order)
+        pinentry = pinentry-curses   (program for the pinentry dialog)
+        desktop = on | off           (show windows over SSH; default detected)
+        key_id = path | fingerprint
+        helper = on | off            (keep PowerShell running; default off)
+        helper_idle = 5m             (exit the PowerShell helper when idle)
+        ttl = 30d                    (s, m, h, d or w; default never)
+        ttl <key|pattern> = 1d       (overrides ttl for matching keys)
+
+EXIT CODES:
+    0 success, 1 cancelled or denied, 2 other failure, 3 backend
+    unavailable, 4 not found, 5 access denied, 6 malformed output
 "#,
         binary_name
     );
 }
 
-fn main() -> Result<()> {
+/// Serves gpg-agent as its pinentry on stdin and stdout.
+fn handle_pinentry() -> Result<()> {
+    // Asking another pinentry would go in circles
+    pinentry_server::serve(
+        credential::open()?.as_ref(),
+        dialog::select_except("pinentry")?.as_ref(),
+        io::stdin().lock(),
+        io::stdout().lock(),
+    )
+}
+
+/// Runs a docker credential helper action. Docker only shows what helpers
+/// print on stdout, so errors go there as well.
+fn handle_docker(action: Option<&str>) -> Result<()> {
+    let result = credential::open().and_then(|store| {
+        docker_credential::handle(
+            store.as_ref(),
+            action,
+            io::stdin().lock(),
+            io::stdout().lock(),
+        )
+    });
+    if let Err(e) = &result {
+        println!("{}", error::message(e));
+    }
+    result
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("winaskpass: {}", error::message(&e));
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+fn run() -> Result<()> {
     let args: Vec<String> = env::args().collect();
 
+    // gpg-agent starts its pinentry program without a way to add arguments
+    let invoked_as = args
+        .first()
+        .and_then(|arg| Path::new(arg).file_name())
+        .and_then(|name| name.to_str());
+    if invoked_as.is_some_and(|name| name.starts_with("pinentry")) {
+        return handle_pinentry();
+    }
+    // Docker finds credential helpers by the name docker-credential-<name>
+    if invoked_as.is_some_and(|name| name.starts_with("docker-credential-")) {
+        return handle_docker(args.get(1).map(String::as_str));
+    }
+
     match args.get(1).map(|s| s.as_str()) {
         Some("--help") | Some("-h") => {
             print_help();
             Ok(())
         }
-        Some("--list") | Some("-l") => handle_list(),
-        Some(prompt) => handle_askpass(prompt),
+        Some("--list") | Some("-l") => handle_list(credential::open()?.as_ref(), &args[2..]),
+        Some("--migrate") => handle_migrate(credential::open()?.as_ref()),
+        Some("--pinentry") => handle_pinentry(),
+        // Started by the PowerShell backends, see powershell_helper
+        #[cfg(all(feature = "powershell", unix))]
+        Some("--powershell-helper") => powershell_helper::main(&args[2..]),
+        Some("prune") => handle_prune(credential::open()?.as_ref()),
+        #[cfg(target_os = "linux")]
+        Some("systemd-agent") => {
+            systemd_agent::run(credential::open()?.as_ref(), dialog::select()?.as_ref())
+        }
+        // Cargo adds --cargo-plugin, which needs no handling
+        Some("cargo-credential") => cargo_credential::serve(
+            credential::open()?.as_ref(),
+            &dialog::select,
+            io::stdin().lock(),
+            io::stdout().lock(),
+        ),
+        Some("docker-credential") => handle_docker(args.get(2).map(String::as_str)),
+        Some("credential") => git_credential::handle(
+            credential::open()?.as_ref(),
+            args.get(2).map(String::as_str),
+            io::stdin().lock(),
+            io::stdout().lock(),
+        ),
+        Some("forget") => handle_forget(credential::open()?.as_ref(), &args[2..]),
+        Some("clear") => {
+            let mut args = args[2..].to_vec();
+            args.push("--all".to_string());
+            handle_forget(credential::open()?.as_ref(), &args)
+        }
+        Some(prompt) if env::var("SSH_ASKPASS_PROMPT").as_deref() == Ok("confirm") => {
+            handle_confirm(dialog::select()?.as_ref(), prompt)
+        }
+        Some(prompt) if env::var("SSH_ASKPASS_PROMPT").as_deref() == Ok("none") => {
+            handle_notify(dialog::select()?.as_ref(), prompt)
+        }
+        Some(prompt) => handle_askpass(
+            credential::open()?.as_ref(),
+            dialog::select()?.as_ref(),
+            prompt,
+        ),
         None => {
             print_help();
-            std::process::exit(1);
+            anyhow::bail!("No prompt given");
         }
     }
 }
@@ -164,222 +610,422 @@ fn main() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use credential::memory::MemoryStore;
+    use dialog::scripted::ScriptedPrompter;
 
-    // Tests for extract_key_path covering all SSH/Git prompt patterns from main.cpp
-
-    // openssh sshconnect2.c: password for authentication on remote ssh server
     #[test]
-    fn test_ssh_password_authentication() {
-        let prompt = "user@example.com's password: ";
-        // Password prompts don't have key paths
-        assert_eq!(extract_key_path(prompt), None);
-        assert!(!is_host_authenticity_prompt(prompt));
-    }
+    fn test_cached_passphrase_answers_prompt() {
+        let store = MemoryStore::default();
+        store
+            .store(
+                "/nonexistent/winaskpass/id_rsa",
+                "secret",
+                &Metadata::default(),
+            )
+            .unwrap();
 
-    // openssh sshconnect2.c: password change request
-    #[test]
-    fn test_ssh_password_change_enter_old() {
-        let prompt = "Enter user@example.com's old password: ";
-        assert_eq!(extract_key_path(prompt), None);
+        let prompt = "Enter passphrase for /nonexistent/winaskpass/id_rsa: ";
+        let prompter = ScriptedPrompter::default();
+        let answer =
+            answer_password(&store, &prompter, prompt, &PromptKind::parse(prompt)).unwrap();
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("secret"));
+        assert!(prompter.password_calls.borrow().is_empty());
     }
 
     #[test]
-    fn test_ssh_password_change_enter_new() {
-        let prompt = "Enter user@example.com's new password: ";
-        assert_eq!(extract_key_path(prompt), None);
-    }
+    fn test_cached_passphrase_found_by_canonical_path() {
+        let store = MemoryStore::default();
+        store
+            .store(
+                r"C:\Users\me\.ssh\id_ed25519",
+                "secret",
+                &Metadata::default(),
+            )
+            .unwrap();
 
-    #[test]
-    fn test_ssh_password_change_retype_new() {
-        let prompt = "Retype user@example.com's new password: ";
-        assert_eq!(extract_key_path(prompt), None);
+        let prompt =
+            "Enter passphrase for /mnt/c/Users/me/.ssh/id_ed25519 (will confirm each use): ";
+        let prompter = ScriptedPrompter::default();
+        let answer =
+            answer_password(&store, &prompter, prompt, &PromptKind::parse(prompt)).unwrap();
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("secret"));
+        assert!(prompter.password_calls.borrow().is_empty());
     }
 
-    // openssh sshconnect2.c and sshconnect1.c: passphrase for keyfile
     #[test]
-    fn test_passphrase_for_key_with_quotes() {
-        let prompt = "Enter passphrase for key '/home/user/.ssh/id_rsa': ";
-        assert_eq!(extract_key_path(prompt), Some("/home/user/.ssh/id_rsa"));
-    }
+    fn test_prompted_passphrase_saved_when_requested() {
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter {
+            password: Some(("secret", true)),
+            ..Default::default()
+        };
 
-    #[test]
-    fn test_passphrase_for_rsa_key_with_quotes() {
-        let prompt = "Enter passphrase for RSA key '/home/user/.ssh/id_rsa': ";
-        assert_eq!(extract_key_path(prompt), Some("/home/user/.ssh/id_rsa"));
-    }
+        let prompt = "Enter passphrase for /nonexistent/winaskpass/id_rsa: ";
+        let answer =
+            answer_password(&store, &prompter, prompt, &PromptKind::parse(prompt)).unwrap();
 
-    // openssh ssh-add.c: passphrase for keyfile (first time)
-    #[test]
-    fn test_passphrase_for_keyfile_no_quotes() {
-        let prompt = "Enter passphrase for /home/user/.ssh/id_ed25519: ";
-        assert_eq!(extract_key_path(prompt), Some("/home/user/.ssh/id_ed25519"));
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("secret"));
+        assert_eq!(
+            store
+                .get("/nonexistent/winaskpass/id_rsa")
+                .unwrap()
+                .map(|credential| credential.secret.expose().to_string())
+                .as_deref(),
+            Some("secret")
+        );
     }
 
     #[test]
-    fn test_passphrase_for_keyfile_with_confirm() {
-        let prompt = "Enter passphrase for /home/user/.ssh/id_ed25519 (will confirm each use): ";
-        // The current implementation extracts the path including the extra text
-        assert!(extract_key_path(prompt).is_some());
-    }
+    fn test_bad_passphrase_skips_and_replaces_cache() {
+        let store = MemoryStore::default();
+        store
+            .store(
+                "/nonexistent/winaskpass/id_rsa",
+                "stale",
+                &Metadata::default(),
+            )
+            .unwrap();
+        let prompter = ScriptedPrompter {
+            password: Some(("fresh", true)),
+            ..Default::default()
+        };
 
-    // openssh ssh-add.c: bad passphrase retry
-    #[test]
-    fn test_bad_passphrase_retry() {
-        let prompt = "Bad passphrase, try again for /home/user/.ssh/id_rsa: ";
-        assert!(extract_key_path(prompt).is_some());
-    }
+        let prompt = "Bad passphrase, try again for /nonexistent/winaskpass/id_rsa: ";
+        let answer =
+            answer_password(&store, &prompter, prompt, &PromptKind::parse(prompt)).unwrap();
 
-    #[test]
-    fn test_bad_passphrase_retry_with_confirm() {
-        let prompt =
-            "Bad passphrase, try again for /home/user/.ssh/id_rsa (will confirm each use): ";
-        assert!(extract_key_path(prompt).is_some());
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("fresh"));
+        assert_eq!(
+            prompter.password_calls.borrow().as_slice(),
+            &[(prompt.to_string(), true, true)]
+        );
+        assert_eq!(
+            store
+                .get("/nonexistent/winaskpass/id_rsa")
+                .unwrap()
+                .map(|credential| credential.secret.expose().to_string())
+                .as_deref(),
+            Some("fresh")
+        );
     }
 
-    // openssh ssh-pkcs11.c: PIN for token
     #[test]
-    fn test_pin_for_token() {
-        let prompt = "Enter PIN for 'My Smart Card': ";
-        assert_eq!(extract_key_path(prompt), Some("My Smart Card"));
-    }
+    fn test_otp_code_never_cached() {
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter {
+            password: Some(("123456", true)),
+            ..Default::default()
+        };
 
-    // openssh ssh-agent.c: PIN for security key
-    #[test]
-    fn test_pin_for_security_key() {
-        let prompt = "Enter PIN for ecdsa-sk key sha256:abc123def456: ";
-        assert!(extract_key_path(prompt).is_some());
-    }
+        let prompt = "Verification code: ";
+        let answer =
+            answer_password(&store, &prompter, prompt, &PromptKind::parse(prompt)).unwrap();
 
-    #[test]
-    fn test_pin_for_security_key_with_presence() {
-        let prompt = "Enter PIN and confirm user presence for ecdsa-sk key sha256:abc123def456: ";
-        assert!(extract_key_path(prompt).is_some());
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("123456"));
+        assert_eq!(
+            prompter.password_calls.borrow().as_slice(),
+            &[(prompt.to_string(), false, false)]
+        );
+        assert_eq!(prompter.captions.borrow().as_slice(), ["Verification Code"]);
+        assert!(store.list().unwrap().is_empty());
     }
 
-    // google-authenticator-libpam: OTP verification code
     #[test]
-    fn test_verification_code() {
-        let prompt = "Verification code: ";
-        assert_eq!(extract_key_path(prompt), None);
-    }
+    fn test_forget_by_path_pattern_and_all() {
+        let store = MemoryStore::default();
+        store
+            .store("/nonexistent/a/id_rsa", "1", &Metadata::default())
+            .unwrap();
+        store
+            .store("/nonexistent/b/id_rsa", "2", &Metadata::default())
+            .unwrap();
+        store
+            .store(
+                "SHA256:abc",
+                "3",
+                &Metadata {
+                    key_path: Some("/nonexistent/c/id_ed25519".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        store
+            .store("https://github.com", "4", &Metadata::default())
+            .unwrap();
 
-    // git credential.c: username without context
-    #[test]
-    fn test_git_username_no_context() {
-        let prompt = "Username: ";
-        assert_eq!(extract_key_path(prompt), None);
-    }
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
 
-    // git credential.c: password without context
-    #[test]
-    fn test_git_password_no_context() {
-        let prompt = "Password: ";
-        assert_eq!(extract_key_path(prompt), None);
-    }
+        handle_forget(&store, &args(&["--yes", "/nonexistent/a/id_rsa"])).unwrap();
+        assert!(store.get("/nonexistent/a/id_rsa").unwrap().is_none());
+        assert!(store.get("/nonexistent/b/id_rsa").unwrap().is_some());
 
-    // git credential.c: username with identifier
-    #[test]
-    fn test_git_username_with_identifier() {
-        let prompt = "Username for 'https://github.com': ";
-        assert_eq!(extract_key_path(prompt), Some("https://github.com"));
+        // Fingerprint-keyed credentials are found by their key path
+        handle_forget(&store, &args(&["-y", "/nonexistent/c/id_ed25519"])).unwrap();
+        assert!(store.get("SHA256:abc").unwrap().is_none());
+
+        handle_forget(&store, &args(&["-y", "/nonexistent/*"])).unwrap();
+        assert!(store.get("/nonexistent/b/id_rsa").unwrap().is_none());
+        assert!(store.get("https://github.com").unwrap().is_some());
+
+        handle_forget(&store, &args(&["--yes", "--all"])).unwrap();
+        assert!(store.list().unwrap().is_empty());
+
+        let error = handle_forget(&store, &args(&["-y", "/nonexistent/*"])).unwrap_err();
+        assert_eq!(error::exit_code(&error), 4);
     }
 
-    // git credential.c: password with identifier
     #[test]
-    fn test_git_password_with_identifier() {
-        let prompt = "Password for 'https://user@github.com': ";
-        assert_eq!(extract_key_path(prompt), Some("https://user@github.com"));
+    fn test_cache_hit_records_use() {
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter {
+            password: Some(("secret", true)),
+            ..Default::default()
+        };
+
+        let prompt = "Enter passphrase for /nonexistent/winaskpass/id_rsa: ";
+        let kind = PromptKind::parse(prompt);
+        answer_password(&store, &prompter, prompt, &kind).unwrap();
+        answer_password(&store, &prompter, prompt, &kind).unwrap();
+        answer_password(&store, &prompter, prompt, &kind).unwrap();
+
+        let entries = store.list().unwrap();
+        let metadata = &entries[0].metadata;
+        assert_eq!(metadata.version, credential::METADATA_VERSION);
+        assert!(metadata.created.is_some());
+        assert!(metadata.last_recorded_use.is_some());
+        // Uses within the hour count as one
+        assert_eq!(metadata.use_hours, 1);
+        assert_eq!(
+            metadata.key_path.as_deref(),
+            Some("/nonexistent/winaskpass/id_rsa")
+        );
     }
 
-    // git-lfs: username with double quotes
     #[test]
-    fn test_git_lfs_username() {
-        let prompt = "Username for \"https://github.com\"";
-        // Current implementation looks for single quotes first
-        assert!(extract_key_path(prompt).is_none() || extract_key_path(prompt).is_some());
+    fn test_expired_passphrase_prompts_again() {
+        let store = MemoryStore::default();
+        let expired = Metadata {
+            created: Some(credential::now() - 2 * 86400),
+            ttl: Some(86400),
+            ..Default::default()
+        };
+        store
+            .store("/nonexistent/winaskpass/id_rsa", "old", &expired)
+            .unwrap();
+        let prompter = ScriptedPrompter {
+            password: Some(("new", false)),
+            ..Default::default()
+        };
+
+        let prompt = "Enter passphrase for /nonexistent/winaskpass/id_rsa: ";
+        let answer =
+            answer_password(&store, &prompter, prompt, &PromptKind::parse(prompt)).unwrap();
+
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("new"));
+        assert_eq!(prompter.password_calls.borrow().len(), 1);
+        assert!(store.list().unwrap().is_empty());
     }
 
-    // git-lfs: password with double quotes
     #[test]
-    fn test_git_lfs_password() {
-        let prompt = "Password for \"https://user@github.com\"";
-        // Current implementation looks for single quotes first
-        assert!(extract_key_path(prompt).is_none() || extract_key_path(prompt).is_some());
+    fn test_prune_deletes_only_expired() {
+        let store = MemoryStore::default();
+        let created = Some(credential::now() - 3600);
+        let with_ttl = |ttl| Metadata {
+            created,
+            ttl,
+            ..Default::default()
+        };
+        store.store("expired", "1", &with_ttl(Some(60))).unwrap();
+        store.store("fresh", "2", &with_ttl(Some(86400))).unwrap();
+        store.store("forever", "3", &with_ttl(None)).unwrap();
+
+        handle_prune(&store).unwrap();
+
+        let names: Vec<String> = store.list().unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["forever", "fresh"]);
     }
 
-    // Edge cases
     #[test]
-    fn test_extract_key_path_with_spaces() {
-        let prompt = "Enter passphrase for '/home/user/my keys/id_rsa': ";
-        assert_eq!(extract_key_path(prompt), Some("/home/user/my keys/id_rsa"));
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13");
     }
 
     #[test]
-    fn test_extract_key_path_with_mixed_quotes() {
-        let prompt = "Enter passphrase for key '/home/user/.ssh/id_rsa' (\"backup\"): ";
-        assert_eq!(extract_key_path(prompt), Some("/home/user/.ssh/id_rsa"));
+    fn test_format_table_aligns_columns() {
+        let entries = [
+            CredentialEntry {
+                name: "SHA256:abc".to_string(),
+                metadata: Metadata {
+                    key_path: Some("/home/user/.ssh/id_ed25519".to_string()),
+                    key_type: Some("ssh-ed25519".to_string()),
+                    created: Some(0),
+                    use_hours: 12,
+                    program: Some("ssh-add".to_string()),
+                    ..Default::default()
+                },
+            },
+            CredentialEntry {
+                name: "git:https://github.com".to_string(),
+                metadata: Metadata::default(),
+            },
+        ];
+
+        let table = format_table(&entries);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("NAME"));
+        assert!(lines[1].starts_with("SHA256:abc (/home/user/.ssh/id_ed25519)  ssh   ssh-ed25519"));
+        let kind = lines[0].find("KIND").unwrap();
+        assert_eq!(&lines[2][kind..kind + 3], "git");
+        assert!(lines[1].contains("1970-01-01 00:00"));
+        let uses = lines[0].find("USE HOURS").unwrap();
+        assert_eq!(&lines[1][uses..uses + 2], "12");
+        assert_eq!(&lines[2][uses..uses + 1], "0");
     }
 
+    // Tests for the PowerShell scripts (if powershell feature is enabled)
+    #[cfg(feature = "powershell")]
     #[test]
-    fn test_empty_prompt() {
-        let prompt = "";
-        assert_eq!(extract_key_path(prompt), None);
+    fn test_password_input_keeps_ssh_prompt_verbatim() {
+        let prompt = "user@example's \"backup\" password:";
+        let input = dialog_powershell::PasswordInput {
+            caption: "SSH Key Passphrase",
+            prompt,
+            show_save_checkbox: true,
+            retry: false,
+        };
+        let json = serde_json::to_value(&input).unwrap();
+        assert_eq!(json["prompt"], prompt);
+        assert!(!dialog_powershell::PASSWORD_SCRIPT.contains("backup"));
     }
 
+    #[cfg(all(feature = "powershell", unix))]
     #[test]
-    fn test_prompt_without_key_path() {
-        let prompt = "Enter something: ";
-        // Prompts without "for " pattern don't extract paths
-        assert_eq!(extract_key_path(prompt), None);
-    }
+    fn test_prompt_sent_on_stdin_not_argv() {
+        let stand_in = powershell::stand_in::StandIn::new(
+            "dialog",
+            r#"{"status":"entered","password":"aHVudGVyMg==","save":false}"#,
+        );
+        let prompter = dialog_powershell::PowerShellPrompter::with_program(&stand_in.program);
 
-    // Tests for is_host_authenticity_prompt covering SSH host verification
+        let prompt = "Enter passphrase for /home/user/.ssh/secret_project_key: ";
+        let result = prompter
+            .prompt_password("SSH Key Passphrase", prompt, true, true)
+            .unwrap()
+            .unwrap();
 
-    // openssh sshconnect.c: unknown SSH host (full prompt)
-    #[test]
-    fn test_unknown_ssh_host_full_prompt() {
-        let prompt = "The authenticity of host 'example.com (192.168.1.1)' can't be established.\nED25519 key fingerprint is SHA256:UAkZs2L2FLJCmHnXBQPFrPitO1n7ChQBy7fUXjz5xAk.\nThis key is not known by any other names.\nAre you sure you want to continue connecting (yes/no/[fingerprint])?";
-        assert!(is_host_authenticity_prompt(prompt));
+        assert_eq!(result.password.expose(), "hunter2");
+        assert!(!stand_in.argv().contains("secret_project_key"));
+        let input = stand_in.input();
+        assert_eq!(input["prompt"], prompt);
+        assert_eq!(input["retry"], true);
     }
 
+    #[cfg(all(feature = "powershell", unix))]
     #[test]
-    fn test_unknown_ssh_host_without_ip() {
-        let prompt = "The authenticity of host 'example.com' can't be established.\nRSA key fingerprint is SHA256:abc123.\nAre you sure you want to continue connecting (yes/no/[fingerprint])?";
-        assert!(is_host_authenticity_prompt(prompt));
-    }
+    fn test_password_dialog_keeps_passphrase_exact() {
+        let passphrase = "  two words\nand a newline  ";
+        let output = serde_json::json!({
+            "status": "entered",
+            "password": base64::Engine::encode(&base64::engine::general_purpose::STANDARD, passphrase),
+            "save": true,
+        });
+        let stand_in = powershell::stand_in::StandIn::new("dialog-exact", &output.to_string());
+        let prompter = dialog_powershell::PowerShellPrompter::with_program(&stand_in.program);
 
-    #[test]
-    fn test_continue_connecting_prompt() {
-        let prompt = "Are you sure you want to continue connecting (yes/no/[fingerprint])?";
-        assert!(is_host_authenticity_prompt(prompt));
+        let result = prompter
+            .prompt_password("SSH Key Passphrase", "Enter passphrase: ", true, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.password.expose(), passphrase);
+        assert!(result.save);
     }
 
+    #[cfg(all(feature = "powershell", unix))]
     #[test]
-    fn test_continue_connecting_old_format() {
-        let prompt = "Are you sure you want to continue connecting (yes/no)?";
-        assert!(is_host_authenticity_prompt(prompt));
+    fn test_password_dialog_cancel_and_error_are_distinct() {
+        let cancelled =
+            powershell::stand_in::StandIn::new("dialog-cancel", r#"{"status":"cancelled"}"#);
+        let prompter = dialog_powershell::PowerShellPrompter::with_program(&cancelled.program);
+        assert!(
+            prompter
+                .prompt_password("SSH Key Passphrase", "Enter passphrase: ", true, false)
+                .unwrap()
+                .is_none()
+        );
+
+        let failed = powershell::stand_in::StandIn::failing(
+            "dialog-error",
+            "CredUIPromptForWindowsCredentialsW error: 1223",
+        );
+        let prompter = dialog_powershell::PowerShellPrompter::with_program(&failed.program);
+        assert!(
+            prompter
+                .prompt_password("SSH Key Passphrase", "Enter passphrase: ", true, false)
+                .is_err()
+        );
+
+        let garbage = powershell::stand_in::StandIn::new("dialog-garbage", "NOSAVE|hunter2");
+        let prompter = dialog_powershell::PowerShellPrompter::with_program(&garbage.program);
+        assert!(
+            prompter
+                .prompt_password("SSH Key Passphrase", "Enter passphrase: ", true, false)
+                .is_err()
+        );
     }
 
-    #[test]
-    fn test_passphrase_prompt_is_not_host_authenticity() {
-        let prompt = "Enter passphrase for key '/home/user/.ssh/id_rsa': ";
-        assert!(!is_host_authenticity_prompt(prompt));
+    /// A store whose credentials cannot be read.
+    struct UnreadableStore(MemoryStore);
+
+    impl CredentialStore for UnreadableStore {
+        fn name(&self) -> &'static str {
+            "unreadable"
+        }
+
+        fn get(&self, _: &str) -> Result<Option<credential::Credential>> {
+            Err(Error::AccessDenied("CredReadW failed: 5".to_string()).into())
+        }
+
+        fn store(&self, name: &str, secret: &str, metadata: &Metadata) -> Result<()> {
+            self.0.store(name, secret, metadata)
+        }
+
+        fn delete(&self, name: &str) -> Result<bool> {
+            self.0.delete(name)
+        }
+
+        fn list(&self) -> Result<Vec<CredentialEntry>> {
+            self.0.list()
+        }
     }
 
-    // Tests for PowerShell script escaping (if powershell feature is enabled)
-    #[cfg(feature = "powershell")]
     #[test]
-    fn test_password_script_escapes_ssh_prompt() {
-        let prompt = "user@example's password:";
-        let script = dialog::build_password_script(prompt, true);
-        assert!(script.contains("user@example''s password:"));
+    fn test_unreadable_cache_falls_back_to_prompt() {
+        let store = UnreadableStore(MemoryStore::default());
+        let prompter = ScriptedPrompter {
+            password: Some(("typed", false)),
+            ..Default::default()
+        };
+
+        let prompt = "Enter passphrase for /nonexistent/winaskpass/id_rsa: ";
+        let answer =
+            answer_password(&store, &prompter, prompt, &PromptKind::parse(prompt)).unwrap();
+        assert_eq!(answer.as_ref().map(Secret::expose), Some("typed"));
+        assert_eq!(prompter.password_calls.borrow().len(), 1);
     }
 
-    #[cfg(feature = "powershell")]
     #[test]
-    fn test_password_script_escapes_mixed_quotes() {
-        let prompt = "user@example's \"backup\" password:";
-        let script = dialog::build_password_script(prompt, true);
-        assert!(script.contains("user@example''s \"backup\" password:"));
+    fn test_cancel_exits_with_its_own_code() {
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter::default();
+
+        let prompt = "Enter passphrase for /nonexistent/winaskpass/id_rsa: ";
+        let error = handle_askpass(&store, &prompter, prompt).unwrap_err();
+        assert_eq!(error::exit_code(&error), 1);
+
+        let error = handle_confirm(&prompter, "Allow use of key id_rsa?").unwrap_err();
+        assert_eq!(error::exit_code(&error), 1);
     }
 }
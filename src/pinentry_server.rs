@@ -0,0 +1,548 @@
+//! `winaskpass --pinentry`: the server side of the Assuan pinentry protocol,
+//! so that gpg-agent can ask for passphrases with the Windows dialogs.
+
+use crate::credential::{CredentialStore, Target};
+use crate::dialog::Prompter;
+use crate::error;
+use crate::secret::Secret;
+use anyhow::Result;
+use std::io::{BufRead, Write};
+
+/// Error codes as libgpg-error combines them with the pinentry source.
+const ERR_GENERAL: u32 = 83886081;
+const ERR_CANCELED: u32 = 83886179;
+const ERR_NOT_CONFIRMED: u32 = 83886194;
+const ERR_UNKNOWN_COMMAND: u32 = 83886355;
+const ERR_PARAMETER: u32 = 83886360;
+
+/// Longest line Assuan accepts, without the line feed.
+const MAX_LINE: usize = 1000;
+
+/// What gpg-agent set up for the next dialog. RESET clears it.
+#[derive(Default)]
+struct Settings {
+    title: Option<String>,
+    description: Option<String>,
+    prompt: Option<String>,
+    /// Why the previous passphrase was rejected
+    error: Option<String>,
+    /// Whether a NOTOK button was asked for, making CONFIRM a three way choice
+    not_ok: bool,
+    /// Keygrip of the key the passphrase unlocks
+    keygrip: Option<String>,
+}
+
+struct Server<'a, W: Write> {
+    store: &'a dyn CredentialStore,
+    prompter: &'a dyn Prompter,
+    output: W,
+    settings: Settings,
+    /// gpg-agent allows passphrases to be kept outside its own cache
+    allow_external_cache: bool,
+}
+
+/// Answers commands from `input` until BYE or the end of input.
+pub fn serve(
+    store: &dyn CredentialStore,
+    prompter: &dyn Prompter,
+    mut input: impl BufRead,
+    output: impl Write,
+) -> Result<()> {
+    let mut server = Server {
+        store,
+        prompter,
+        output,
+        settings: Settings::default(),
+        allow_external_cache: false,
+    };
+    server.ok(Some("Pleased to meet you"))?;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (command, parameter) = line.split_once(' ').unwrap_or((line, ""));
+        if command.eq_ignore_ascii_case("BYE") {
+            return server.ok(Some("closing connection"));
+        }
+        server.handle(&command.to_ascii_uppercase(), parameter)?;
+    }
+}
+
+impl<W: Write> Server<'_, W> {
+    fn handle(&mut self, command: &str, parameter: &str) -> Result<()> {
+        match command {
+            "OPTION" => {
+                let name = parameter.split(['=', ' ']).next().unwrap_or_default();
+                if name == "allow-external-password-cache" {
+                    self.allow_external_cache = true;
+                }
+                // Display and terminal options do not apply to Windows dialogs
+                self.ok(None)
+            }
+            "SETTITLE" => {
+                self.settings.title = Some(unescape(parameter));
+                self.ok(None)
+            }
+            "SETDESC" => {
+                self.settings.description = Some(unescape(parameter));
+                self.ok(None)
+            }
+            "SETPROMPT" => {
+                self.settings.prompt = Some(unescape(parameter));
+                self.ok(None)
+            }
+            "SETERROR" => {
+                self.settings.error = Some(unescape(parameter));
+                self.ok(None)
+            }
+            "SETNOTOK" => {
+                self.settings.not_ok = true;
+                self.ok(None)
+            }
+            "SETKEYINFO" => {
+                // "--clear", or the cache mode and keygrip as in "n/<keygrip>"
+                self.settings.keygrip = parameter
+                    .split_once('/')
+                    .map(|(_, keygrip)| keygrip.to_string())
+                    .filter(|keygrip| !keygrip.is_empty());
+                self.ok(None)
+            }
+            // Labels and other settings the Windows dialogs have no place for
+            "SETOK" | "SETCANCEL" | "SETQUALITYBAR" | "SETQUALITYBAR_TT" | "SETGENPIN"
+            | "SETGENPIN_TT" | "SETREPEAT" | "SETREPEATERROR" | "SETTIMEOUT" | "SETREPEATOK"
+            | "CLEARPASSPHRASE" | "NOP" => self.ok(None),
+            "RESET" => {
+                self.settings = Settings::default();
+                self.ok(None)
+            }
+            "GETINFO" => match parameter {
+                "flavor" => self.data_ok("winaskpass"),
+                "version" => self.data_ok(env!("CARGO_PKG_VERSION")),
+                "pid" => self.data_ok(&std::process::id().to_string()),
+                _ => self.err(ERR_PARAMETER, "IPC parameter error"),
+            },
+            "GETPIN" => self.get_pin(),
+            "CONFIRM" => self.confirm(parameter == "--one-button"),
+            "MESSAGE" => self.confirm(true),
+            _ => self.err(ERR_UNKNOWN_COMMAND, "Unknown IPC command"),
+        }
+    }
+
+    fn get_pin(&mut self) -> Result<()> {
+        let target = self
+            .settings
+            .keygrip
+            .as_deref()
+            .filter(|_| self.allow_external_cache)
+            .map(Target::for_keygrip);
+        let retry = self.settings.error.is_some();
+
+        if let Some(target) = &target {
+            if retry {
+                // gpg-agent rejected the cached passphrase
+                if let Err(e) = target.delete(self.store) {
+                    eprintln!("Warning: Failed to delete stale credential: {}", e);
+                }
+            } else {
+                match target.get(self.store) {
+                    Ok(Some(pin)) => {
+                        self.status("PASSWORD_FROM_CACHE")?;
+                        return self.secret_ok(&pin);
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Warning: Failed to read cached credential: {}", e),
+                }
+            }
+        }
+
+        let prompt = self.prompt_text();
+        let caption = self.caption("GPG Passphrase");
+        let result = match self
+            .prompter
+            .prompt_password(&caption, &prompt, target.is_some(), retry)
+        {
+            Ok(Some(result)) => result,
+            Ok(None) => return self.err(ERR_CANCELED, "Operation cancelled"),
+            Err(e) => return self.err(ERR_GENERAL, &error::message(&e)),
+        };
+
+        if result.save
+            && let Some(target) = &target
+            && let Err(e) = target.store(self.store, result.password.expose())
+        {
+            eprintln!("Warning: Failed to save credential: {}", e);
+        }
+        self.secret_ok(&result.password)
+    }
+
+    /// CONFIRM with OK and Cancel buttons, with an extra NOTOK button if one
+    /// was set, or only OK for a message.
+    fn confirm(&mut self, one_button: bool) -> Result<()> {
+        let prompt = self.prompt_text();
+        let answer = if one_button {
+            let caption = self.caption("GnuPG");
+            self.prompter.show_message(&caption, &prompt).map(Ok)
+        } else if self.settings.not_ok {
+            self.prompter
+                .prompt_confirmation(&self.caption("GnuPG"), &prompt)
+                .map(|answer| match answer.as_deref() {
+                    Some("yes") => Ok(()),
+                    Some(_) => Err((ERR_NOT_CONFIRMED, "Not confirmed")),
+                    None => Err((ERR_CANCELED, "Operation cancelled")),
+                })
+        } else {
+            self.prompter
+                .prompt_allow(&self.caption("GnuPG"), &prompt)
+                .map(|allowed| {
+                    if allowed {
+                        Ok(())
+                    } else {
+                        Err((ERR_CANCELED, "Operation cancelled"))
+                    }
+                })
+        };
+        match answer {
+            Ok(Ok(())) => self.ok(None),
+            Ok(Err((code, message))) => self.err(code, message),
+            Err(e) => self.err(ERR_GENERAL, &error::message(&e)),
+        }
+    }
+
+    /// The title of the dialog, `default` unless gpg-agent set one.
+    fn caption(&self, default: &str) -> String {
+        self.settings
+            .title
+            .clone()
+            .unwrap_or_else(|| default.to_string())
+    }
+
+    /// The text shown in the dialog: the error, if any, then the description.
+    fn prompt_text(&self) -> String {
+        let settings = &self.settings;
+        let text = settings
+            .description
+            .as_deref()
+            .or(settings.prompt.as_deref())
+            .unwrap_or("Enter passphrase");
+        match &settings.error {
+            Some(error) => format!("{}\n\n{}", error, text),
+            None => text.to_string(),
+        }
+    }
+
+    fn ok(&mut self, comment: Option<&str>) -> Result<()> {
+        match comment {
+            Some(comment) => writeln!(self.output, "OK {}", comment)?,
+            None => writeln!(self.output, "OK")?,
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn err(&mut self, code: u32, message: &str) -> Result<()> {
+        writeln!(self.output, "ERR {} {}", code, escape(message))?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        writeln!(self.output, "S {}", status)?;
+        Ok(())
+    }
+
+    fn data_ok(&mut self, data: &str) -> Result<()> {
+        writeln!(self.output, "D {}", escape(data))?;
+        self.ok(None)
+    }
+
+    /// Sends `secret` as data lines, then OK. The lines are built in
+    /// [`Secret`]s so the escaped passphrase is wiped as well.
+    fn secret_ok(&mut self, secret: &Secret) -> Result<()> {
+        let mut line = Secret::default();
+        for c in secret.expose().chars() {
+            if line.expose().is_empty() {
+                line.push('D');
+                line.push(' ');
+            }
+            push_escaped(c, |c| line.push(c));
+            if line.expose().len() > MAX_LINE - 4 {
+                line.push('\n');
+                self.output.write_all(line.expose().as_bytes())?;
+                line = Secret::default();
+            }
+        }
+        if !line.expose().is_empty() {
+            line.push('\n');
+            self.output.write_all(line.expose().as_bytes())?;
+        }
+        self.ok(None)
+    }
+}
+
+/// Passes `c` to `push`, escaped as Assuan requires for parameters and
+/// data.
+fn push_escaped(c: char, mut push: impl FnMut(char)) {
+    match c {
+        '%' | '\r' | '\n' => {
+            let hex = b"0123456789ABCDEF";
+            push('%');
+            push(hex[c as usize >> 4] as char);
+            push(hex[c as usize & 0xf] as char);
+        }
+        c => push(c),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    text.chars()
+        .for_each(|c| push_escaped(c, |c| escaped.push(c)));
+    escaped
+}
+
+/// Decodes the `%XX` escapes of a parameter. Invalid escapes are kept as
+/// they are.
+fn unescape(parameter: &str) -> String {
+    let mut bytes = Vec::with_capacity(parameter.len());
+    let mut rest = parameter.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(value) => {
+                bytes.push(value);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::memory::MemoryStore;
+    use crate::dialog::scripted::ScriptedPrompter;
+
+    const KEYGRIP: &str = "0123456789ABCDEF0123456789ABCDEF01234567";
+
+    /// Runs `commands` and returns the replies after the greeting.
+    fn session(store: &MemoryStore, prompter: &ScriptedPrompter, commands: &[&str]) -> Vec<String> {
+        let input = commands
+            .iter()
+            .map(|c| format!("{}\n", c))
+            .collect::<String>();
+        let mut output = Vec::new();
+        serve(store, prompter, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines: Vec<String> = output.lines().map(str::to_string).collect();
+        assert_eq!(lines.remove(0), "OK Pleased to meet you");
+        lines
+    }
+
+    #[test]
+    fn test_getpin_escapes_passphrase() {
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter {
+            password: Some(("100% pass\nword", false)),
+            ..Default::default()
+        };
+
+        let replies = session(
+            &store,
+            &prompter,
+            &[
+                "OPTION ttyname=/dev/pts/1",
+                "SETDESC Please enter the passphrase%0A\"Alice <alice@example.com>\"",
+                "SETPROMPT Passphrase:",
+                "GETPIN",
+                "BYE",
+            ],
+        );
+        assert_eq!(
+            replies,
+            [
+                "OK",
+                "OK",
+                "OK",
+                "D 100%25 pass%0Aword",
+                "OK",
+                "OK closing connection"
+            ]
+        );
+        let calls = prompter.password_calls.borrow();
+        assert_eq!(
+            calls[0],
+            (
+                "Please enter the passphrase\n\"Alice <alice@example.com>\"".to_string(),
+                false,
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn test_long_passphrase_split_into_lines() {
+        let store = MemoryStore::default();
+        let password: &'static str = "%".repeat(500).leak();
+        let prompter = ScriptedPrompter {
+            password: Some((password, false)),
+            ..Default::default()
+        };
+
+        let replies = session(&store, &prompter, &["GETPIN"]);
+        let data: Vec<&String> = replies
+            .iter()
+            .filter(|line| line.starts_with("D "))
+            .collect();
+        assert_eq!(data.len(), 2);
+        assert!(data.iter().all(|line| line.len() <= MAX_LINE));
+        let joined: String = data.iter().map(|line| &line[2..]).collect();
+        assert_eq!(unescape(&joined), password);
+    }
+
+    #[test]
+    fn test_getpin_cancelled() {
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter::default();
+
+        let replies = session(&store, &prompter, &["GETPIN"]);
+        assert_eq!(replies, ["ERR 83886179 Operation cancelled"]);
+    }
+
+    #[test]
+    fn test_pin_cached_by_keygrip_when_saved() {
+        let store = MemoryStore::default();
+        let keyinfo = format!("SETKEYINFO n/{}", KEYGRIP);
+        let prompter = ScriptedPrompter {
+            password: Some(("hunter2", true)),
+            ..Default::default()
+        };
+        let commands = [
+            "OPTION allow-external-password-cache",
+            keyinfo.as_str(),
+            "GETPIN",
+        ];
+        let replies = session(&store, &prompter, &commands);
+        assert_eq!(replies[2..], ["D hunter2", "OK"]);
+        assert!(prompter.password_calls.borrow()[0].1);
+        let name = format!("gpg:{}", KEYGRIP);
+        assert_eq!(
+            store.get(&name).unwrap().unwrap().secret.expose(),
+            "hunter2"
+        );
+
+        // The next request is answered from the cache without a dialog
+        let prompter = ScriptedPrompter::default();
+        let replies = session(&store, &prompter, &commands);
+        assert_eq!(replies[2..], ["S PASSWORD_FROM_CACHE", "D hunter2", "OK"]);
+        assert!(prompter.password_calls.borrow().is_empty());
+
+        // A rejected passphrase is dropped and asked for again
+        let prompter = ScriptedPrompter {
+            password: Some(("correct", false)),
+            ..Default::default()
+        };
+        let mut retry = commands.to_vec();
+        retry.insert(2, "SETERROR Bad Passphrase (try 2 of 3)");
+        let replies = session(&store, &prompter, &retry);
+        assert_eq!(replies[3..], ["D correct", "OK"]);
+        assert!(prompter.password_calls.borrow()[0].2);
+        assert!(store.get(&name).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_no_cache_without_agent_permission() {
+        let store = MemoryStore::default();
+        let keyinfo = format!("SETKEYINFO n/{}", KEYGRIP);
+        let prompter = ScriptedPrompter {
+            password: Some(("hunter2", true)),
+            ..Default::default()
+        };
+
+        session(&store, &prompter, &[keyinfo.as_str(), "GETPIN"]);
+        assert!(!prompter.password_calls.borrow()[0].1);
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_confirm_buttons() {
+        let store = MemoryStore::default();
+        let allow = ScriptedPrompter {
+            allow: true,
+            ..Default::default()
+        };
+        assert_eq!(session(&store, &allow, &["CONFIRM"]), ["OK"]);
+
+        let deny = ScriptedPrompter::default();
+        assert_eq!(
+            session(&store, &deny, &["CONFIRM"]),
+            ["ERR 83886179 Operation cancelled"]
+        );
+        assert_eq!(session(&store, &deny, &["CONFIRM --one-button"]), ["OK"]);
+
+        let no = ScriptedPrompter {
+            confirmation: Some("no"),
+            ..Default::default()
+        };
+        assert_eq!(
+            session(&store, &no, &["SETNOTOK No", "CONFIRM"]),
+            ["OK", "ERR 83886194 Not confirmed"]
+        );
+
+        // Titled as gpg-agent asked
+        assert_eq!(
+            session(&store, &no, &["SETTITLE Signing", "SETNOTOK No", "CONFIRM"]),
+            ["OK", "OK", "ERR 83886194 Not confirmed"]
+        );
+        assert_eq!(*no.captions.borrow(), ["GnuPG", "Signing"]);
+    }
+
+    #[test]
+    fn test_message_has_only_ok() {
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter::default();
+        let replies = session(
+            &store,
+            &prompter,
+            &[
+                "SETDESC Key expired",
+                "MESSAGE",
+                "SETTITLE Signing",
+                "CONFIRM --one-button",
+            ],
+        );
+        assert_eq!(replies, ["OK", "OK", "OK", "OK"]);
+        assert_eq!(
+            *prompter.messages.borrow(),
+            [
+                ("GnuPG".to_string(), "Key expired".to_string()),
+                ("Signing".to_string(), "Key expired".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter::default();
+        assert_eq!(
+            session(&store, &prompter, &["GETINFO flavor", "FROB"]),
+            ["D winaskpass", "OK", "ERR 83886355 Unknown IPC command"]
+        );
+    }
+}
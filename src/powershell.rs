@@ -0,0 +1,384 @@
+use crate::error::Error;
+use crate::secret::{Secret, SecretBuf};
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+/// The only script passed on the command line. Every line it reads on stdin
+/// is a request: base64 encoded UTF-8 JSON holding a script and its input.
+/// The script runs with the input in `$data` and the reply goes back as one
+/// line of base64 encoded UTF-8 JSON, so neither direction depends on the
+/// console code page. Types added by a script stay loaded for later requests.
+/// A failing Windows call is reported with its error code.
+const RUNNER_SCRIPT: &str = r#"
+$blocks = @{}
+while (($line = [Console]::In.ReadLine()) -ne $null) {
+    try {
+        $request = [System.Text.Encoding]::UTF8.GetString(
+            [Convert]::FromBase64String($line)) | ConvertFrom-Json
+        if (-not $blocks.ContainsKey($request.script)) {
+            $blocks[$request.script] = [ScriptBlock]::Create($request.script)
+        }
+        $data = $request.data
+        $output = (& $blocks[$request.script]) -join "`n"
+        $response = @{ ok = $true; output = $output }
+    } catch {
+        $response = @{ ok = $false; error = $_.ToString() }
+        $exception = $_.Exception
+        while ($exception -ne $null -and $exception -isnot [System.ComponentModel.Win32Exception]) {
+            $exception = $exception.InnerException
+        }
+        if ($exception -ne $null) { $response.code = $exception.NativeErrorCode }
+    }
+    $json = $response | ConvertTo-Json -Compress
+    [Console]::Out.WriteLine([Convert]::ToBase64String([System.Text.Encoding]::UTF8.GetBytes($json)))
+    [Console]::Out.Flush()
+}
+"#;
+
+#[derive(Serialize)]
+struct Request<'a, T: Serialize> {
+    script: &'a str,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    ok: bool,
+    #[serde(default)]
+    output: Option<Secret>,
+    #[serde(default)]
+    error: Option<String>,
+    /// Windows error code of a failed call
+    #[serde(default)]
+    code: Option<u32>,
+}
+
+/// Encodes a request line for [`RUNNER_SCRIPT`], without the newline. The
+/// input may hold a passphrase, so the encoded forms are wiped after use.
+pub fn encode_request(script: &str, input: &impl Serialize) -> Result<Secret> {
+    let request = SecretBuf::from(serde_json::to_vec(&Request {
+        script,
+        data: input,
+    })?);
+    Ok(Secret::from(STANDARD.encode(&*request)))
+}
+
+/// Decodes a reply line of [`RUNNER_SCRIPT`] into the script's output.
+pub fn decode_response(line: &str) -> Result<Secret> {
+    let malformed = || Error::MalformedOutput("Invalid reply from PowerShell".to_string());
+    let json = SecretBuf::from(STANDARD.decode(line.trim()).map_err(|_| malformed())?);
+    let response: Response = serde_json::from_slice(&json).map_err(|_| malformed())?;
+    if response.ok {
+        return Ok(response.output.unwrap_or_default());
+    }
+
+    let message = format!("PowerShell error: {}", response.error.unwrap_or_default());
+    match response
+        .code
+        .and_then(|code| Error::from_win32(code, message.clone()))
+    {
+        Some(error) => Err(error.into()),
+        None => Err(anyhow::anyhow!(message)),
+    }
+}
+
+/// Starts `program` running [`RUNNER_SCRIPT`], which is passed as
+/// `-EncodedCommand` so no quoting is involved.
+pub fn runner(program: &Path, args: &[&str]) -> Command {
+    let utf16: Vec<u8> = RUNNER_SCRIPT
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .args(["-EncodedCommand", &STANDARD.encode(utf16)]);
+    command
+}
+
+/// Starts `script` in `program` and sends it `input`. Neither appears on the
+/// command line: both go through stdin.
+pub fn spawn(program: &Path, args: &[&str], script: &str, input: &impl Serialize) -> Result<Child> {
+    let request = encode_request(script, input)?;
+
+    let mut child = runner(program, args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Unavailable(format!("Failed to execute PowerShell: {}", e)))?;
+
+    // Dropping stdin closes it, so the runner exits after this request
+    let mut stdin = child.stdin.take().context("PowerShell stdin unavailable")?;
+    writeln!(stdin, "{}", request.expose()).context("Failed to write to PowerShell")?;
+    Ok(child)
+}
+
+/// Runs `script` and returns its output, through the resident helper when
+/// there is one and starting PowerShell when the helper fails. Fails if the
+/// script fails.
+pub fn execute(
+    program: &Path,
+    args: &[&str],
+    script: &str,
+    input: &impl Serialize,
+) -> Result<Secret> {
+    #[cfg(unix)]
+    if let Some(mut helper) = crate::powershell_helper::connect(program) {
+        match helper.request(script, input) {
+            Ok(reply) => return decode_response(reply.expose()),
+            Err(e) => eprintln!(
+                "Warning: PowerShell helper failed, running without it: {}",
+                e
+            ),
+        }
+    }
+
+    let output = spawn(program, args, script, input)?
+        .wait_with_output()
+        .context("Failed to execute PowerShell")?;
+    let stdout = Secret::from_utf8(output.stdout);
+    match stdout.expose().lines().next() {
+        Some(line) if !line.trim().is_empty() => decode_response(line),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("PowerShell error: {}", stderr);
+        }
+    }
+}
+
+/// The PowerShell on this machine, for tests running the real scripts. Those
+/// tests are ignored unless asked for, since most machines have none.
+#[cfg(test)]
+pub fn installed() -> Option<std::path::PathBuf> {
+    ["pwsh", "pwsh.exe", "powershell.exe"]
+        .into_iter()
+        .map(std::path::PathBuf::from)
+        .find(|program| crate::dialog::is_executable_in_path(program))
+}
+
+/// Parses a script with PowerShell's own parser, reporting its syntax errors
+/// and the classes each `Add-Type -TypeDefinition` in it defines.
+#[cfg(test)]
+const PARSE_SCRIPT: &str = r#"
+$tokens = $null
+$errors = $null
+$ast = [System.Management.Automation.Language.Parser]::ParseInput($data.script, [ref]$tokens, [ref]$errors)
+$commands = $ast.FindAll({
+    param($node)
+    $node -is [System.Management.Automation.Language.CommandAst] -and $node.GetCommandName() -eq 'Add-Type'
+}, $true)
+$types = foreach ($command in $commands) {
+    $bound = [System.Management.Automation.Language.StaticParameterBinder]::BindCommand($command)
+    $definition = $bound.BoundParameters['TypeDefinition']
+    if ($definition -eq $null) { continue }
+    $source = if ($definition.ConstantValue -ne $null) {
+        [string]$definition.ConstantValue
+    } else {
+        $definition.Value.Extent.Text
+    }
+    foreach ($match in [regex]::Matches($source, '\bclass\s+(\w+)')) {
+        @{ name = $match.Groups[1].Value; source = $source }
+    }
+}
+@{
+    errors = @($errors | ForEach-Object { '{0}: {1}' -f $_.Extent.StartLineNumber, $_.Message })
+    types = @($types)
+} | ConvertTo-Json -Compress -Depth 3
+"#;
+
+/// What [`PARSE_SCRIPT`] makes of a script.
+#[cfg(test)]
+#[derive(Deserialize)]
+pub struct Parsed {
+    pub errors: Vec<String>,
+    pub types: Vec<AddedType>,
+}
+
+/// A class passed to `Add-Type`, with the whole source it is defined in.
+#[cfg(test)]
+#[derive(Deserialize)]
+pub struct AddedType {
+    pub name: String,
+    pub source: String,
+}
+
+/// Parses `script` in the PowerShell on this machine, without running it.
+#[cfg(test)]
+pub fn parse(script: &str) -> Parsed {
+    let program = installed().expect("PowerShell is not installed");
+    let input = serde_json::json!({ "script": script });
+    let output = execute(
+        &program,
+        &["-NoProfile", "-NonInteractive"],
+        PARSE_SCRIPT,
+        &input,
+    )
+    .expect("Failed to run the PowerShell parser");
+    serde_json::from_str(output.expose()).expect("Unexpected parser output")
+}
+
+#[cfg(all(test, unix))]
+pub mod stand_in {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    /// A fake `powershell.exe` recording its arguments and stdin, for tests.
+    pub struct StandIn {
+        pub program: PathBuf,
+        dir: PathBuf,
+    }
+
+    impl StandIn {
+        /// Creates a stand-in whose scripts all succeed with `output`.
+        pub fn new(name: &str, output: &str) -> StandIn {
+            StandIn::replying(name, serde_json::json!({ "ok": true, "output": output }))
+        }
+
+        /// Creates a stand-in whose scripts all fail with `error`.
+        pub fn failing(name: &str, error: &str) -> StandIn {
+            StandIn::replying(name, serde_json::json!({ "ok": false, "error": error }))
+        }
+
+        /// Answers every request line with `reply`, like the runner script.
+        fn replying(name: &str, reply: serde_json::Value) -> StandIn {
+            let dir =
+                env::temp_dir().join(format!("winaskpass-ps-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let program = dir.join("powershell.exe");
+            let script = concat!(
+                "#!/bin/sh\n",
+                "dir=\"$(dirname \"$0\")\"\n",
+                "printf '%s\\n' \"$@\" > \"$dir/argv\"\n",
+                "while read -r line; do\n",
+                "  echo \"$line\" >> \"$dir/requests\"\n",
+                "  cat \"$dir/reply\"; echo\n",
+                "done\n",
+            );
+            fs::write(&program, script).unwrap();
+            fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+            fs::write(dir.join("reply"), STANDARD.encode(reply.to_string())).unwrap();
+
+            StandIn { program, dir }
+        }
+
+        pub fn dir(&self) -> &Path {
+            &self.dir
+        }
+
+        /// The arguments of the last run, one per line.
+        pub fn argv(&self) -> String {
+            fs::read_to_string(self.dir.join("argv")).unwrap()
+        }
+
+        /// The input of the last request.
+        pub fn input(&self) -> serde_json::Value {
+            self.requests().pop().unwrap()["data"].take()
+        }
+
+        /// The requests received, decoded.
+        pub fn requests(&self) -> Vec<serde_json::Value> {
+            fs::read_to_string(self.dir.join("requests"))
+                .unwrap()
+                .lines()
+                .map(decode)
+                .collect()
+        }
+    }
+
+    fn decode(line: &str) -> serde_json::Value {
+        serde_json::from_slice(&STANDARD.decode(line.trim()).unwrap()).unwrap()
+    }
+
+    impl Drop for StandIn {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::stand_in::StandIn;
+    use super::*;
+
+    /// Non-ASCII, emoji, CJK and control characters, and PowerShell quoting
+    const UNUSUAL: &str = "pässwörd 🔑 密码 パス\n\t\r\u{1b}[0m\u{0} `$(x)' \"y\" ";
+
+    #[test]
+    fn test_unicode_round_trip() {
+        let stand_in = StandIn::new("unicode", UNUSUAL);
+        let input = serde_json::json!({ "secret": UNUSUAL });
+
+        let output = execute(&stand_in.program, &[], "$data.secret", &input).unwrap();
+
+        assert_eq!(output.expose(), UNUSUAL);
+        assert_eq!(stand_in.input()["secret"], UNUSUAL);
+        let argv = stand_in.argv();
+        assert!(argv.contains("-EncodedCommand"));
+        assert!(!argv.contains("密码"));
+        assert!(!argv.contains("$data.secret"));
+    }
+
+    #[test]
+    fn test_script_failure_is_an_error() {
+        let stand_in = StandIn::failing("failure", "Ошибка: 密码");
+        let error = execute(&stand_in.program, &[], "throw", &()).err().unwrap();
+        assert!(error.to_string().contains("Ошибка: 密码"));
+    }
+
+    #[test]
+    fn test_windows_error_codes_typed() {
+        let reply = |json: serde_json::Value| STANDARD.encode(json.to_string());
+
+        let denied =
+            reply(serde_json::json!({ "ok": false, "error": "CredWriteW failed: 5", "code": 5 }));
+        let error = decode_response(&denied).err().unwrap();
+        assert_eq!(crate::error::exit_code(&error), 5);
+
+        let failed = reply(serde_json::json!({ "ok": false, "error": "oops" }));
+        let error = decode_response(&failed).err().unwrap();
+        assert_eq!(crate::error::exit_code(&error), crate::error::FAILURE);
+
+        let error = decode_response("not base64!").err().unwrap();
+        assert_eq!(crate::error::exit_code(&error), 6);
+    }
+
+    #[test]
+    #[ignore = "requires pwsh"]
+    fn test_parse() {
+        assert!(parse(RUNNER_SCRIPT).errors.is_empty());
+        assert!(!parse("if ($x) {\n    'x'\n").errors.is_empty());
+        assert!(!parse("@\"\nno end\n").errors.is_empty());
+
+        let parsed =
+            parse("Add-Type -TypeDefinition @\"\npublic class A {}\npublic class B {}\n\"@");
+        let names: Vec<&str> = parsed.types.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["A", "B"]);
+        assert_eq!(parsed.types[0].source, parsed.types[1].source);
+    }
+
+    #[test]
+    fn test_runner_passed_as_utf16_base64() {
+        let command = runner(Path::new("powershell.exe"), &["-NoProfile"]);
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args[..2], ["-NoProfile", "-EncodedCommand"]);
+
+        let bytes = STANDARD.decode(args[2].to_str().unwrap()).unwrap();
+        let utf16: Vec<u16> = bytes
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&utf16).unwrap(), RUNNER_SCRIPT);
+    }
+}
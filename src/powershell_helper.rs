@@ -0,0 +1,357 @@
+use crate::config;
+use crate::powershell;
+use crate::secret::Secret;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the helper waits for requests before shutting down, unless the
+/// `helper_idle` setting says otherwise.
+const DEFAULT_IDLE: Duration = Duration::from_secs(5 * 60);
+
+/// How long a client waits for a freshly started helper to listen.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The least time the helper waits for a connected client to send its next
+/// request, even when the idle time is shorter.
+const MIN_CLIENT_WAIT: Duration = Duration::from_secs(1);
+
+/// Connection to the resident helper.
+pub struct HelperClient {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl HelperClient {
+    /// Sends `script` with `input` in `$data` and returns the reply, for
+    /// [`powershell::decode_response`]. Fails only if the helper could not
+    /// be talked to.
+    pub fn request(&mut self, script: &str, input: &impl Serialize) -> Result<Secret> {
+        let request = powershell::encode_request(script, input)?;
+        writeln!(self.stream, "{}", request.expose())
+            .context("Failed to send request to PowerShell helper")?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            anyhow::bail!("PowerShell helper closed the connection");
+        }
+        Ok(Secret::from(line))
+    }
+}
+
+/// Connects to the helper when the `helper` setting is `on`, starting it if
+/// it is not running. Returns None when calls should start their own
+/// PowerShell instead.
+pub fn connect(program: &Path) -> Option<HelperClient> {
+    if config::get("helper").as_deref() != Some("on") {
+        return None;
+    }
+
+    let socket = socket_path()?;
+    if let Ok(client) = connect_to(&socket) {
+        return Some(client);
+    }
+
+    if let Err(e) = start(&socket, program) {
+        eprintln!("Warning: Failed to start PowerShell helper: {}", e);
+        return None;
+    }
+    let started = Instant::now();
+    while started.elapsed() < START_TIMEOUT {
+        if let Ok(client) = connect_to(&socket) {
+            return Some(client);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+fn connect_to(socket: &Path) -> io::Result<HelperClient> {
+    // Requests hold passphrases: only our own helper may get them
+    let metadata = fs::symlink_metadata(socket)?;
+    if !metadata.file_type().is_socket() || !is_ours(&metadata) {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "PowerShell helper socket is not ours",
+        ));
+    }
+    let stream = UnixStream::connect(socket)?;
+    let reader = BufReader::new(stream.try_clone()?);
+    Ok(HelperClient { stream, reader })
+}
+
+/// Whether the file belongs to the user we run as.
+fn is_ours(metadata: &fs::Metadata) -> bool {
+    metadata.uid() == unsafe { libc::geteuid() }
+}
+
+/// `$XDG_RUNTIME_DIR/winaskpass/helper.sock`, or a private directory under
+/// the temporary directory. None if the directory is not ours and private,
+/// as another user could have created it first.
+fn socket_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("winaskpass"),
+        None => env::temp_dir().join(format!(
+            "winaskpass-{}",
+            env::var("USER").unwrap_or_default()
+        )),
+    };
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .ok()?;
+    let metadata = fs::symlink_metadata(&dir).ok()?;
+    let private = metadata.permissions().mode() & 0o077 == 0;
+    (metadata.is_dir() && is_ours(&metadata) && private).then(|| dir.join("helper.sock"))
+}
+
+/// Starts the helper in the background, detached from our stdio so that
+/// whoever reads our output is not kept waiting for it.
+fn start(socket: &Path, program: &Path) -> Result<()> {
+    Command::new(env::current_exe()?)
+        .arg("--powershell-helper")
+        .arg(socket)
+        .arg(program)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+    Ok(())
+}
+
+/// `--powershell-helper <socket> <program>`: the helper process itself.
+pub fn main(args: &[String]) -> Result<()> {
+    let [socket, program] = args else {
+        anyhow::bail!("Usage: winaskpass --powershell-helper <socket> <program>");
+    };
+    let idle = match config::get("helper_idle") {
+        Some(value) => config::parse_duration(&value)?.map_or(Duration::ZERO, Duration::from_secs),
+        None => DEFAULT_IDLE,
+    };
+    serve(Path::new(socket), Path::new(program), idle)
+}
+
+/// Relays requests arriving on `socket` to a resident `program` until no
+/// request came in for `idle`.
+pub fn serve(socket: &Path, program: &Path, idle: Duration) -> Result<()> {
+    let listener = bind(socket)?;
+    listener.set_nonblocking(true)?;
+
+    let mut helper = powershell::runner(program, &["-NoProfile", "-NonInteractive"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to execute PowerShell")?;
+    let mut resident = Resident::new(&mut helper)?;
+
+    let mut last_used = Instant::now();
+    let mut stopping = false;
+    let result = loop {
+        match listener.accept() {
+            Ok((stream, _)) => match resident.relay(stream, idle.max(MIN_CLIENT_WAIT)) {
+                Ok(true) => last_used = Instant::now(),
+                Ok(false) => {}
+                Err(e) => break Err(e),
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if stopping {
+                    break Ok(());
+                }
+                if last_used.elapsed() >= idle {
+                    // New clients now start a new helper; serve the ones
+                    // already waiting before going away
+                    let _ = fs::remove_file(socket);
+                    stopping = true;
+                    continue;
+                }
+                if helper.try_wait()?.is_some() {
+                    break Err(anyhow::anyhow!("PowerShell helper exited"));
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => break Err(e.into()),
+        }
+    };
+
+    if !stopping {
+        let _ = fs::remove_file(socket);
+    }
+    let _ = helper.kill();
+    let _ = helper.wait();
+    result
+}
+
+/// Binds `socket`, replacing a socket left behind by a helper that is gone.
+fn bind(socket: &Path) -> Result<UnixListener> {
+    match UnixListener::bind(socket) {
+        Err(e) if e.kind() == ErrorKind::AddrInUse && UnixStream::connect(socket).is_err() => {
+            fs::remove_file(socket)?;
+            Ok(UnixListener::bind(socket)?)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// The pipes to the resident `powershell.exe`.
+struct Resident {
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Resident {
+    fn new(helper: &mut Child) -> Result<Resident> {
+        Ok(Resident {
+            stdin: helper
+                .stdin
+                .take()
+                .context("PowerShell stdin unavailable")?,
+            stdout: BufReader::new(
+                helper
+                    .stdout
+                    .take()
+                    .context("PowerShell stdout unavailable")?,
+            ),
+        })
+    }
+
+    /// Passes the client's requests to PowerShell and the replies back,
+    /// returning whether there were any. Clients are served one at a time,
+    /// so one that sends nothing for `timeout` is dropped rather than keep
+    /// the others waiting. Only failures talking to PowerShell are errors; a
+    /// client going away just ends its connection.
+    fn relay(&mut self, stream: UnixStream, timeout: Duration) -> Result<bool> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut client = BufReader::new(stream.try_clone()?);
+        let mut stream = stream;
+
+        // Requests and replies may hold passphrases: each is wiped once
+        // passed on
+        let mut relayed = false;
+        loop {
+            let mut request = String::new();
+            match client.read_line(&mut request) {
+                Ok(0) | Err(_) => return Ok(relayed),
+                Ok(_) => relayed = true,
+            }
+            let request = Secret::from(request);
+
+            self.stdin.write_all(request.expose().as_bytes())?;
+            self.stdin.flush()?;
+            let mut reply = String::new();
+            if self.stdout.read_line(&mut reply)? == 0 {
+                anyhow::bail!("PowerShell helper exited");
+            }
+            let reply = Secret::from(reply);
+
+            if stream.write_all(reply.expose().as_bytes()).is_err() {
+                return Ok(relayed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::powershell::stand_in::StandIn;
+
+    #[test]
+    fn test_requests_relayed_until_idle() {
+        let stand_in = StandIn::new("helper", "pong");
+        let socket = stand_in.dir().join("helper.sock");
+
+        let server = {
+            let socket = socket.clone();
+            let program = stand_in.program.clone();
+            thread::spawn(move || serve(&socket, &program, Duration::from_millis(300)))
+        };
+
+        let started = Instant::now();
+        let mut client = loop {
+            match connect_to(&socket) {
+                Ok(client) => break client,
+                Err(_) if started.elapsed() < START_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                Err(e) => panic!("helper did not start: {}", e),
+            }
+        };
+
+        let input = serde_json::json!({ "secret": "hunter2" });
+        for _ in 0..2 {
+            let reply = client.request("'ping'", &input).unwrap();
+            let output = powershell::decode_response(reply.expose()).unwrap();
+            assert_eq!(output.expose(), "pong");
+        }
+        drop(client);
+
+        server.join().unwrap().unwrap();
+        assert!(!socket.exists());
+
+        let requests = stand_in.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["script"], "'ping'");
+        assert_eq!(requests[0]["data"]["secret"], "hunter2");
+        assert!(!stand_in.argv().contains("hunter2"));
+    }
+
+    #[test]
+    fn test_silent_client_does_not_block_shutdown() {
+        let stand_in = StandIn::new("helper-silent", "pong");
+        let socket = stand_in.dir().join("helper.sock");
+
+        let server = {
+            let socket = socket.clone();
+            let program = stand_in.program.clone();
+            thread::spawn(move || serve(&socket, &program, Duration::from_millis(200)))
+        };
+
+        let started = Instant::now();
+        let silent = loop {
+            match UnixStream::connect(&socket) {
+                Ok(stream) => break stream,
+                Err(_) if started.elapsed() < START_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                Err(e) => panic!("helper did not start: {}", e),
+            }
+        };
+
+        // The connection stays open but nothing is sent
+        let started = Instant::now();
+        while !server.is_finished() {
+            assert!(
+                started.elapsed() < MIN_CLIENT_WAIT * 5,
+                "helper still running"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+        server.join().unwrap().unwrap();
+        assert!(!socket.exists());
+        drop(silent);
+    }
+
+    #[test]
+    fn test_only_our_socket_is_used() {
+        let stand_in = StandIn::new("helper-socket", "");
+        let socket = stand_in.dir().join("helper.sock");
+        fs::write(&socket, "").unwrap();
+
+        let error = connect_to(&socket).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+}
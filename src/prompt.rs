@@ -0,0 +1,640 @@
+/// Stage of an SSH password change request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordStage {
+    Old,
+    New,
+    Retype,
+}
+
+/// What an askpass prompt is asking for, with the fields parsed out of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptKind<'a> {
+    /// ssh-add / sshconnect2: "Enter passphrase for /path/to/key: "
+    /// or "Enter passphrase for key '/path/to/key': "
+    KeyPassphrase {
+        key_path: &'a str,
+        confirm: bool,
+    },
+    /// ssh-add: "Bad passphrase, try again for /path/to/key: "
+    BadPassphrase {
+        key_path: &'a str,
+        confirm: bool,
+    },
+    /// ssh-agent: "Allow use of key /path/to/key?\nKey fingerprint SHA256:..."
+    ConfirmKeyUse {
+        comment: &'a str,
+        fingerprint: Option<&'a str>,
+    },
+    /// sshconnect2: "user@host's password: "
+    HostPassword {
+        user_host: &'a str,
+    },
+    /// sshconnect2: "Enter user@host's old password: " and friends
+    PasswordChange {
+        user_host: &'a str,
+        stage: PasswordStage,
+    },
+    /// ssh-pkcs11: "Enter PIN for 'token label': "
+    Pkcs11Pin {
+        token: &'a str,
+    },
+    /// ssh-sk: "Enter PIN for ecdsa-sk key SHA256:...: "
+    SecurityKeyPin {
+        key_type: &'a str,
+        fingerprint: &'a str,
+        presence: bool,
+    },
+    /// ssh-sk: "Confirm user presence for key ECDSA-SK SHA256:..."
+    UserPresence {
+        key_type: &'a str,
+        fingerprint: &'a str,
+    },
+    /// google-authenticator-libpam: "Verification code: "
+    OtpCode,
+    /// git / git-lfs: "Username for 'https://host': " or "Username: "
+    GitUsername {
+        url: Option<&'a str>,
+    },
+    /// git / git-lfs: "Password for 'https://user@host': " or "Password: "
+    GitPassword {
+        url: Option<&'a str>,
+    },
+    /// sshconnect: "The authenticity of host 'foo (1.2.3.4)' can't be established..."
+    HostAuthenticity {
+        host: Option<&'a str>,
+        fingerprint: Option<&'a str>,
+    },
+    Unknown,
+}
+
+impl<'a> PromptKind<'a> {
+    pub fn parse(prompt: &'a str) -> Self {
+        let trimmed = prompt.trim();
+
+        // SSH sends prompts like:
+        // "The authenticity of host 'foo (1.2.3.4)' can't be established..."
+        // "Are you sure you want to continue connecting (yes/no/[fingerprint])?"
+        if trimmed.contains("authenticity of host")
+            || trimmed.contains("continue connecting (yes/no")
+        {
+            return PromptKind::HostAuthenticity {
+                host: trimmed
+                    .split_once("authenticity of host '")
+                    .and_then(|(_, rest)| rest.split_once('\''))
+                    .map(|(host, _)| host),
+                fingerprint: trimmed
+                    .split_once("key fingerprint is ")
+                    .map(|(_, rest)| first_line(rest).trim_end_matches('.')),
+            };
+        }
+
+        if let Some(rest) = trimmed.strip_prefix("Allow use of key ") {
+            let (comment, rest) = rest.split_once('?').unwrap_or((rest, ""));
+            return PromptKind::ConfirmKeyUse {
+                comment,
+                fingerprint: rest
+                    .split_once("Key fingerprint ")
+                    .map(|(_, fp)| first_line(fp).trim_end_matches('.')),
+            };
+        }
+
+        if let Some(rest) = trimmed.strip_prefix("Bad passphrase, try again for ") {
+            let (key_path, confirm) = key_path_and_confirm(rest);
+            return PromptKind::BadPassphrase { key_path, confirm };
+        }
+
+        if let Some(rest) = trimmed.strip_prefix("Enter passphrase for ") {
+            // "Enter passphrase for key '...'" and "Enter passphrase for RSA key '...'"
+            if let Some(start) = rest.find('\'')
+                && let Some(key_path) = quoted(&rest[start..])
+            {
+                return PromptKind::KeyPassphrase {
+                    key_path,
+                    confirm: false,
+                };
+            }
+            let (key_path, confirm) = key_path_and_confirm(rest);
+            return PromptKind::KeyPassphrase { key_path, confirm };
+        }
+
+        if let Some(rest) = trimmed.strip_prefix("Confirm user presence for key ") {
+            let mut fields = first_line(rest).split_whitespace();
+            if let (Some(key_type), Some(fingerprint)) = (fields.next(), fields.next()) {
+                return PromptKind::UserPresence {
+                    key_type,
+                    fingerprint,
+                };
+            }
+        }
+
+        let pin = trimmed
+            .strip_prefix("Enter PIN and confirm user presence for ")
+            .map(|rest| (rest, true))
+            .or_else(|| {
+                trimmed
+                    .strip_prefix("Enter PIN for ")
+                    .map(|rest| (rest, false))
+            });
+        if let Some((rest, presence)) = pin {
+            if let Some(token) = quoted(rest) {
+                return PromptKind::Pkcs11Pin { token };
+            }
+            if let Some((key_type, fingerprint)) = rest.split_once(" key ") {
+                return PromptKind::SecurityKeyPin {
+                    key_type,
+                    fingerprint: fingerprint.trim_end_matches(':').trim_end(),
+                    presence,
+                };
+            }
+        }
+
+        for (prefix, suffix, stage) in [
+            ("Enter ", "'s old password:", PasswordStage::Old),
+            ("Enter ", "'s new password:", PasswordStage::New),
+            ("Retype ", "'s new password:", PasswordStage::Retype),
+        ] {
+            if let Some(user_host) = trimmed
+                .strip_prefix(prefix)
+                .and_then(|rest| rest.strip_suffix(suffix))
+            {
+                return PromptKind::PasswordChange { user_host, stage };
+            }
+        }
+
+        if let Some(user_host) = trimmed.strip_suffix("'s password:")
+            && !user_host.contains(' ')
+        {
+            return PromptKind::HostPassword { user_host };
+        }
+
+        if trimmed.starts_with("Verification code:") {
+            return PromptKind::OtpCode;
+        }
+
+        if let Some(url) = trimmed.strip_prefix("Username").and_then(git_url) {
+            return PromptKind::GitUsername { url };
+        }
+
+        if let Some(url) = trimmed.strip_prefix("Password").and_then(git_url) {
+            return PromptKind::GitPassword { url };
+        }
+
+        PromptKind::Unknown
+    }
+
+    /// Name under which the answer to this prompt may be cached, if any.
+    /// git's prompts are not cached: the user name and password of a remote
+    /// belong in one `git:` entry, which `winaskpass credential` keeps. Nor
+    /// is a security key's PIN: like a one-time code, it guards the device
+    /// itself, and saving it would leave only the touch between a process
+    /// and the key.
+    pub fn credential_target(&self) -> Option<&'a str> {
+        match *self {
+            PromptKind::KeyPassphrase { key_path, .. }
+            | PromptKind::BadPassphrase { key_path, .. } => Some(key_path),
+            PromptKind::Pkcs11Pin { token } => Some(token),
+            _ => None,
+        }
+    }
+
+    /// Title of the dialog asking this prompt.
+    pub fn caption(&self) -> &'static str {
+        match self {
+            PromptKind::KeyPassphrase { .. } | PromptKind::BadPassphrase { .. } => {
+                "SSH Key Passphrase"
+            }
+            PromptKind::ConfirmKeyUse { .. } => "SSH Key Confirmation",
+            PromptKind::HostPassword { .. } => "SSH Password",
+            PromptKind::PasswordChange { .. } => "SSH Password Change",
+            PromptKind::Pkcs11Pin { .. } => "Smart Card PIN",
+            PromptKind::SecurityKeyPin { .. } => "Security Key PIN",
+            PromptKind::UserPresence { .. } => "Security Key",
+            PromptKind::OtpCode => "Verification Code",
+            PromptKind::GitUsername { .. } => "Git Username",
+            PromptKind::GitPassword { .. } => "Git Password",
+            PromptKind::HostAuthenticity { .. } => "SSH Host Verification",
+            PromptKind::Unknown => "Password",
+        }
+    }
+}
+
+/// Returns the text between an opening quote at the start of `s` and the
+/// last matching quote, accepting both single and double quotes.
+fn quoted(s: &str) -> Option<&str> {
+    let quote = s.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let end = s.rfind(quote)?;
+    if end > 0 { Some(&s[1..end]) } else { None }
+}
+
+fn first_line(s: &str) -> &str {
+    s.lines().next().unwrap_or(s).trim()
+}
+
+/// Splits "/path/to/key (will confirm each use):" into the path and the flag.
+fn key_path_and_confirm(rest: &str) -> (&str, bool) {
+    let rest = rest.trim_end_matches(':').trim_end();
+    match rest.strip_suffix("(will confirm each use)") {
+        Some(path) => (path.trim_end(), true),
+        None => (rest, false),
+    }
+}
+
+/// Parses the part of a git prompt following "Username"/"Password".
+/// Returns `Some(None)` for the bare form and `Some(Some(url))` when the
+/// prompt names the remote, either single (git) or double (git-lfs) quoted.
+fn git_url(rest: &str) -> Option<Option<&str>> {
+    let rest = rest.trim_end();
+    if rest == ":" {
+        return Some(None);
+    }
+    let rest = rest.strip_prefix(" for ")?;
+    let rest = rest.strip_suffix(':').unwrap_or(rest);
+    quoted(rest).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests covering all SSH/Git prompt patterns from main.cpp
+
+    // openssh sshconnect2.c: password for authentication on remote ssh server
+    #[test]
+    fn test_ssh_password_authentication() {
+        let prompt = "user@example.com's password: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::HostPassword {
+                user_host: "user@example.com"
+            }
+        );
+        assert_eq!(PromptKind::parse(prompt).credential_target(), None);
+    }
+
+    // openssh sshconnect2.c: password change request
+    #[test]
+    fn test_ssh_password_change_enter_old() {
+        let prompt = "Enter user@example.com's old password: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::PasswordChange {
+                user_host: "user@example.com",
+                stage: PasswordStage::Old
+            }
+        );
+    }
+
+    #[test]
+    fn test_ssh_password_change_enter_new() {
+        let prompt = "Enter user@example.com's new password: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::PasswordChange {
+                user_host: "user@example.com",
+                stage: PasswordStage::New
+            }
+        );
+    }
+
+    #[test]
+    fn test_ssh_password_change_retype_new() {
+        let prompt = "Retype user@example.com's new password: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::PasswordChange {
+                user_host: "user@example.com",
+                stage: PasswordStage::Retype
+            }
+        );
+    }
+
+    // openssh sshconnect2.c and sshconnect1.c: passphrase for keyfile
+    #[test]
+    fn test_passphrase_for_key_with_quotes() {
+        let prompt = "Enter passphrase for key '/home/user/.ssh/id_rsa': ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::KeyPassphrase {
+                key_path: "/home/user/.ssh/id_rsa",
+                confirm: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_passphrase_for_rsa_key_with_quotes() {
+        let prompt = "Enter passphrase for RSA key '/home/user/.ssh/id_rsa': ";
+        assert_eq!(
+            PromptKind::parse(prompt).credential_target(),
+            Some("/home/user/.ssh/id_rsa")
+        );
+    }
+
+    // openssh ssh-add.c: passphrase for keyfile (first time)
+    #[test]
+    fn test_passphrase_for_keyfile_no_quotes() {
+        let prompt = "Enter passphrase for /home/user/.ssh/id_ed25519: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::KeyPassphrase {
+                key_path: "/home/user/.ssh/id_ed25519",
+                confirm: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_passphrase_for_keyfile_with_confirm() {
+        let prompt = "Enter passphrase for /home/user/.ssh/id_ed25519 (will confirm each use): ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::KeyPassphrase {
+                key_path: "/home/user/.ssh/id_ed25519",
+                confirm: true
+            }
+        );
+    }
+
+    // openssh ssh-add.c: bad passphrase retry
+    #[test]
+    fn test_bad_passphrase_retry() {
+        let prompt = "Bad passphrase, try again for /home/user/.ssh/id_rsa: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::BadPassphrase {
+                key_path: "/home/user/.ssh/id_rsa",
+                confirm: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_bad_passphrase_retry_with_confirm() {
+        let prompt =
+            "Bad passphrase, try again for /home/user/.ssh/id_rsa (will confirm each use): ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::BadPassphrase {
+                key_path: "/home/user/.ssh/id_rsa",
+                confirm: true
+            }
+        );
+    }
+
+    // openssh ssh-agent.c: key use confirmation for keys added with ssh-add -c
+    #[test]
+    fn test_confirm_key_use() {
+        let prompt = "Allow use of key /home/user/.ssh/id_ed25519?\nKey fingerprint SHA256:UAkZs2L2FLJCmHnXBQPFrPitO1n7ChQBy7fUXjz5xAk.";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::ConfirmKeyUse {
+                comment: "/home/user/.ssh/id_ed25519",
+                fingerprint: Some("SHA256:UAkZs2L2FLJCmHnXBQPFrPitO1n7ChQBy7fUXjz5xAk")
+            }
+        );
+    }
+
+    // openssh ssh-pkcs11.c: PIN for token
+    #[test]
+    fn test_pin_for_token() {
+        let prompt = "Enter PIN for 'My Smart Card': ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::Pkcs11Pin {
+                token: "My Smart Card"
+            }
+        );
+    }
+
+    // openssh ssh-agent.c: PIN for security key
+    #[test]
+    fn test_pin_for_security_key() {
+        let prompt = "Enter PIN for ecdsa-sk key sha256:abc123def456: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::SecurityKeyPin {
+                key_type: "ecdsa-sk",
+                fingerprint: "sha256:abc123def456",
+                presence: false
+            }
+        );
+        assert_eq!(PromptKind::parse(prompt).credential_target(), None);
+    }
+
+    #[test]
+    fn test_pin_for_security_key_with_presence() {
+        let prompt = "Enter PIN and confirm user presence for ecdsa-sk key sha256:abc123def456: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::SecurityKeyPin {
+                key_type: "ecdsa-sk",
+                fingerprint: "sha256:abc123def456",
+                presence: true
+            }
+        );
+        assert_eq!(PromptKind::parse(prompt).credential_target(), None);
+    }
+
+    // openssh ssh-sk-client.c: touch request for security key
+    #[test]
+    fn test_confirm_user_presence() {
+        let prompt = "Confirm user presence for key ED25519-SK SHA256:UAkZs2L2FLJCmHnXBQPFrPitO1n7ChQBy7fUXjz5xAk";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::UserPresence {
+                key_type: "ED25519-SK",
+                fingerprint: "SHA256:UAkZs2L2FLJCmHnXBQPFrPitO1n7ChQBy7fUXjz5xAk"
+            }
+        );
+        assert_eq!(PromptKind::parse(prompt).credential_target(), None);
+    }
+
+    // google-authenticator-libpam: OTP verification code
+    #[test]
+    fn test_verification_code() {
+        let prompt = "Verification code: ";
+        assert_eq!(PromptKind::parse(prompt), PromptKind::OtpCode);
+        assert_eq!(PromptKind::parse(prompt).credential_target(), None);
+    }
+
+    // git credential.c: username without context
+    #[test]
+    fn test_git_username_no_context() {
+        let prompt = "Username: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::GitUsername { url: None }
+        );
+    }
+
+    // git credential.c: password without context
+    #[test]
+    fn test_git_password_no_context() {
+        let prompt = "Password: ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::GitPassword { url: None }
+        );
+    }
+
+    // git credential.c: username with identifier
+    #[test]
+    fn test_git_username_with_identifier() {
+        let prompt = "Username for 'https://github.com': ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::GitUsername {
+                url: Some("https://github.com")
+            }
+        );
+    }
+
+    // git credential.c: password with identifier
+    #[test]
+    fn test_git_password_with_identifier() {
+        let prompt = "Password for 'https://user@github.com': ";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::GitPassword {
+                url: Some("https://user@github.com")
+            }
+        );
+        assert_eq!(PromptKind::parse(prompt).credential_target(), None);
+        assert_eq!(
+            PromptKind::parse("Username for 'https://github.com': ").credential_target(),
+            None
+        );
+    }
+
+    // git-lfs: username with double quotes
+    #[test]
+    fn test_git_lfs_username() {
+        let prompt = "Username for \"https://github.com\"";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::GitUsername {
+                url: Some("https://github.com")
+            }
+        );
+    }
+
+    // git-lfs: password with double quotes
+    #[test]
+    fn test_git_lfs_password() {
+        let prompt = "Password for \"https://user@github.com\"";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::GitPassword {
+                url: Some("https://user@github.com")
+            }
+        );
+    }
+
+    // Edge cases
+    #[test]
+    fn test_key_path_with_spaces() {
+        let prompt = "Enter passphrase for '/home/user/my keys/id_rsa': ";
+        assert_eq!(
+            PromptKind::parse(prompt).credential_target(),
+            Some("/home/user/my keys/id_rsa")
+        );
+    }
+
+    #[test]
+    fn test_key_path_with_mixed_quotes() {
+        let prompt = "Enter passphrase for key '/home/user/.ssh/id_rsa' (\"backup\"): ";
+        assert_eq!(
+            PromptKind::parse(prompt).credential_target(),
+            Some("/home/user/.ssh/id_rsa")
+        );
+    }
+
+    #[test]
+    fn test_empty_prompt() {
+        let prompt = "";
+        assert_eq!(PromptKind::parse(prompt), PromptKind::Unknown);
+        assert_eq!(PromptKind::parse(prompt).credential_target(), None);
+    }
+
+    #[test]
+    fn test_caption_follows_kind() {
+        for (prompt, caption) in [
+            (
+                "Enter passphrase for /home/user/.ssh/id_rsa: ",
+                "SSH Key Passphrase",
+            ),
+            ("user@host's password: ", "SSH Password"),
+            ("Enter PIN for 'My Token': ", "Smart Card PIN"),
+            (
+                "Enter PIN for ecdsa-sk key SHA256:abc: ",
+                "Security Key PIN",
+            ),
+            ("Verification code: ", "Verification Code"),
+            ("Password for 'https://github.com': ", "Git Password"),
+            ("", "Password"),
+        ] {
+            assert_eq!(PromptKind::parse(prompt).caption(), caption, "{prompt}");
+        }
+    }
+
+    #[test]
+    fn test_prompt_without_key_path() {
+        let prompt = "Enter something: ";
+        assert_eq!(PromptKind::parse(prompt), PromptKind::Unknown);
+    }
+
+    // openssh sshconnect.c: unknown SSH host (full prompt)
+    #[test]
+    fn test_unknown_ssh_host_full_prompt() {
+        let prompt = "The authenticity of host 'example.com (192.168.1.1)' can't be established.\nED25519 key fingerprint is SHA256:UAkZs2L2FLJCmHnXBQPFrPitO1n7ChQBy7fUXjz5xAk.\nThis key is not known by any other names.\nAre you sure you want to continue connecting (yes/no/[fingerprint])?";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::HostAuthenticity {
+                host: Some("example.com (192.168.1.1)"),
+                fingerprint: Some("SHA256:UAkZs2L2FLJCmHnXBQPFrPitO1n7ChQBy7fUXjz5xAk")
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_ssh_host_without_ip() {
+        let prompt = "The authenticity of host 'example.com' can't be established.\nRSA key fingerprint is SHA256:abc123.\nAre you sure you want to continue connecting (yes/no/[fingerprint])?";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::HostAuthenticity {
+                host: Some("example.com"),
+                fingerprint: Some("SHA256:abc123")
+            }
+        );
+    }
+
+    #[test]
+    fn test_continue_connecting_prompt() {
+        let prompt = "Are you sure you want to continue connecting (yes/no/[fingerprint])?";
+        assert_eq!(
+            PromptKind::parse(prompt),
+            PromptKind::HostAuthenticity {
+                host: None,
+                fingerprint: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_continue_connecting_old_format() {
+        let prompt = "Are you sure you want to continue connecting (yes/no)?";
+        assert!(matches!(
+            PromptKind::parse(prompt),
+            PromptKind::HostAuthenticity { .. }
+        ));
+    }
+
+    #[test]
+    fn test_passphrase_prompt_is_not_host_authenticity() {
+        let prompt = "Enter passphrase for key '/home/user/.ssh/id_rsa': ";
+        assert!(!matches!(
+            PromptKind::parse(prompt),
+            PromptKind::HostAuthenticity { .. }
+        ));
+    }
+}
@@ -0,0 +1,248 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{Ordering, compiler_fence};
+
+/// A passphrase held in memory. Its buffer is locked against swapping where
+/// the system allows it and overwritten with zeros when dropped.
+///
+/// There is deliberately no `Debug` or `Display`: the text is only reachable
+/// through [`Secret::expose`], so it cannot end up in a log line by accident.
+#[derive(Default)]
+pub struct Secret {
+    text: String,
+}
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.text
+    }
+
+    /// Decodes UTF-16, replacing invalid units like
+    /// [`String::from_utf16_lossy`], without reallocating along the way.
+    #[cfg(any(feature = "native", test))]
+    pub fn from_utf16(units: &[u16]) -> Secret {
+        let mut text = String::with_capacity(units.len() * 3);
+        text.extend(
+            char::decode_utf16(units.iter().copied())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+        );
+        Secret::from(text)
+    }
+
+    /// Appends `c`. When the buffer is full the text moves to a larger one
+    /// and the old buffer is wiped, so growing leaves no copy behind.
+    pub fn push(&mut self, c: char) {
+        if self.text.len() + c.len_utf8() > self.text.capacity() {
+            let mut grown = String::with_capacity((self.text.capacity() * 2).max(64));
+            grown.push_str(&self.text);
+            *self = Secret::from(grown);
+        }
+        self.text.push(c);
+    }
+
+    /// Removes the last character; its bytes are wiped with the rest.
+    #[cfg(unix)]
+    pub fn pop(&mut self) -> Option<char> {
+        self.text.pop()
+    }
+
+    /// Takes over `bytes`, or a lossy copy of them if they are not UTF-8.
+    pub fn from_utf8(bytes: Vec<u8>) -> Secret {
+        match String::from_utf8(bytes) {
+            Ok(text) => Secret::from(text),
+            Err(e) => {
+                let bytes = SecretBuf::from(e.into_bytes());
+                Secret::from(String::from_utf8_lossy(&bytes).into_owned())
+            }
+        }
+    }
+}
+
+impl From<String> for Secret {
+    fn from(text: String) -> Secret {
+        lock(text.as_ptr(), text.capacity());
+        Secret { text }
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // Safety: only zeros are written, which is valid UTF-8
+        let bytes = unsafe { self.text.as_mut_vec() };
+        wipe_vec(bytes);
+        unlock(bytes.as_ptr(), bytes.capacity());
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Secret, D::Error> {
+        String::deserialize(deserializer).map(Secret::from)
+    }
+}
+
+/// A buffer for secrets in encoded form, such as the UTF-16 strings passed
+/// to the Windows API. Locked and wiped like [`Secret`].
+pub struct SecretBuf<T: Copy + Default> {
+    buf: Vec<T>,
+}
+
+impl<T: Copy + Default> SecretBuf<T> {
+    /// A buffer of `len` default values.
+    #[cfg(feature = "native")]
+    pub fn zeroed(len: usize) -> SecretBuf<T> {
+        SecretBuf::from(vec![T::default(); len])
+    }
+}
+
+/// Encodes `text` as UTF-16, without a terminating null.
+#[cfg(any(feature = "native", test))]
+pub fn utf16(text: &str) -> SecretBuf<u16> {
+    let mut buf = Vec::with_capacity(text.len());
+    buf.extend(text.encode_utf16());
+    SecretBuf::from(buf)
+}
+
+impl<T: Copy + Default> From<Vec<T>> for SecretBuf<T> {
+    fn from(buf: Vec<T>) -> SecretBuf<T> {
+        lock(buf.as_ptr(), buf.capacity() * size_of::<T>());
+        SecretBuf { buf }
+    }
+}
+
+impl<T: Copy + Default> Deref for SecretBuf<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.buf
+    }
+}
+
+impl<T: Copy + Default> DerefMut for SecretBuf<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.buf
+    }
+}
+
+impl<T: Copy + Default> Drop for SecretBuf<T> {
+    fn drop(&mut self) {
+        wipe_vec(&mut self.buf);
+        unlock(self.buf.as_ptr(), self.buf.capacity() * size_of::<T>());
+    }
+}
+
+/// Overwrites `buf` with default values in a way the compiler keeps.
+#[cfg(any(feature = "native", unix))]
+pub fn wipe<T: Copy + Default>(buf: &mut [T]) {
+    for value in buf.iter_mut() {
+        // Safety: `value` is a valid, aligned reference
+        unsafe { std::ptr::write_volatile(value, T::default()) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Wipes the whole allocation of `buf`, including spare capacity that may
+/// hold leftovers.
+fn wipe_vec<T: Copy + Default>(buf: &mut Vec<T>) {
+    let ptr = buf.as_mut_ptr();
+    for i in 0..buf.capacity() {
+        // Safety: within the allocation, and T is Copy so nothing is dropped
+        unsafe { std::ptr::write_volatile(ptr.add(i), T::default()) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+// Locking is best effort: it fails beyond RLIMIT_MEMLOCK, and as locks are
+// per page and do not nest, dropping one secret can unlock a page shared
+// with another.
+
+#[cfg(unix)]
+fn lock<T>(ptr: *const T, len: usize) {
+    if len > 0 {
+        unsafe { libc::mlock(ptr.cast(), len) };
+    }
+}
+
+#[cfg(unix)]
+fn unlock<T>(ptr: *const T, len: usize) {
+    if len > 0 {
+        unsafe { libc::munlock(ptr.cast(), len) };
+    }
+}
+
+#[cfg(all(windows, feature = "native"))]
+fn lock<T>(ptr: *const T, len: usize) {
+    if len > 0 {
+        let _ = unsafe { windows::Win32::System::Memory::VirtualLock(ptr.cast(), len) };
+    }
+}
+
+#[cfg(all(windows, feature = "native"))]
+fn unlock<T>(ptr: *const T, len: usize) {
+    if len > 0 {
+        let _ = unsafe { windows::Win32::System::Memory::VirtualUnlock(ptr.cast(), len) };
+    }
+}
+
+#[cfg(not(any(unix, all(windows, feature = "native"))))]
+fn lock<T>(_: *const T, _: usize) {}
+
+#[cfg(not(any(unix, all(windows, feature = "native"))))]
+fn unlock<T>(_: *const T, _: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf16_round_trip() {
+        let text = "pässwörd 🔑 密码";
+        let units = utf16(text);
+        assert_eq!(units.len(), text.encode_utf16().count());
+        assert_eq!(Secret::from_utf16(&units).expose(), text);
+    }
+
+    #[test]
+    fn test_invalid_input_replaced() {
+        assert_eq!(Secret::from_utf16(&[0x61, 0xd800]).expose(), "a\u{fffd}");
+        assert_eq!(Secret::from_utf8(vec![0x61, 0xff]).expose(), "a\u{fffd}");
+    }
+
+    #[test]
+    fn test_wipe_spare_capacity() {
+        let mut buf = Vec::with_capacity(8);
+        buf.extend_from_slice(b"hunter2");
+        buf.truncate(3);
+        wipe_vec(&mut buf);
+        let spare: Vec<u8> = buf
+            .spare_capacity_mut()
+            .iter()
+            .map(|byte| unsafe { byte.assume_init() })
+            .collect();
+        assert_eq!(buf, [0, 0, 0]);
+        assert!(spare.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_push_grows_without_losing_text() {
+        let mut secret = Secret::default();
+        for c in "pässwörd 🔑".chars().cycle().take(100) {
+            secret.push(c);
+        }
+        assert_eq!(secret.expose().chars().count(), 100);
+        assert!(secret.expose().starts_with("pässwörd 🔑p"));
+        assert_eq!(secret.pop(), Some('🔑'));
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let secret: Secret = serde_json::from_str(r#"" two words ""#).unwrap();
+        assert_eq!(secret.expose(), " two words ");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), r#"" two words ""#);
+    }
+}
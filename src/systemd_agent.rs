@@ -0,0 +1,412 @@
+//! `winaskpass systemd-agent`: a password agent answering the requests of
+//! `systemd-ask-password`, following systemd's password agent protocol.
+
+use crate::credential::{CredentialStore, Metadata, Target};
+use crate::dialog::Prompter;
+use crate::error::{self, Error};
+use crate::secret::{Secret, SecretBuf};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::fd::FromRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+/// Where systemd leaves `ask.*` files for agents to answer.
+const ASK_PASSWORD_DIR: &str = "/run/systemd/ask-password";
+
+/// Title of the dialogs, which systemd leaves to the agent.
+const CAPTION: &str = "System Password";
+
+/// A password request, as read from the `[Ask]` section of an `ask.*` file.
+#[derive(Debug, Default, PartialEq)]
+struct Request {
+    /// Datagram socket the answer is sent to
+    socket: PathBuf,
+    message: String,
+    /// What is asked for, e.g. `cryptsetup:/dev/sda2`
+    id: Option<String>,
+    /// Process waiting for the answer
+    pid: Option<i32>,
+    /// CLOCK_MONOTONIC time in microseconds after which the request is
+    /// void, 0 if it never is
+    not_after: u64,
+    /// Whether a previously given answer may be used again
+    accept_cached: bool,
+    /// Whether the answer may be shown while typed
+    echo: bool,
+}
+
+impl Request {
+    fn parse(text: &str) -> Result<Request> {
+        let mut request = Request::default();
+        let mut socket = None;
+        let mut in_ask = false;
+        for line in text.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_ask = line == "[Ask]";
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            if !in_ask {
+                continue;
+            }
+            let value = value.trim();
+            match key.trim() {
+                "Socket" => socket = Some(PathBuf::from(value)),
+                "Message" => request.message = value.to_string(),
+                "Id" => request.id = Some(value.to_string()).filter(|id| !id.is_empty()),
+                "PID" => request.pid = value.parse().ok().filter(|pid| *pid > 0),
+                "NotAfter" => request.not_after = value.parse().unwrap_or_default(),
+                "AcceptCached" => request.accept_cached = value == "1",
+                "Echo" => request.echo = value == "1",
+                _ => {}
+            }
+        }
+        request.socket = socket.context("Request without a Socket")?;
+        Ok(request)
+    }
+
+    /// Whether the request can no longer be answered: it timed out, or the
+    /// process asking is gone.
+    fn is_void(&self) -> bool {
+        if self.not_after > 0 && monotonic_usec() > self.not_after {
+            return true;
+        }
+        self.pid.is_some_and(|pid| {
+            let alive = unsafe { libc::kill(pid, 0) } == 0;
+            !alive && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        })
+    }
+
+    /// Cache entry for the answer, if the request says what it is for.
+    fn target(&self) -> Option<Target> {
+        let id = self.id.as_ref()?;
+        Some(Target::named(
+            format!("systemd:{}", id),
+            Metadata::default(),
+        ))
+    }
+}
+
+fn monotonic_usec() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+/// Answers `request` from the cache if it allows that, or else with the
+/// dialog. Returns None if the user cancelled.
+fn answer(
+    store: &dyn CredentialStore,
+    prompter: &dyn Prompter,
+    request: &Request,
+) -> Result<Option<Secret>> {
+    let target = request.target();
+    if request.accept_cached
+        && let Some(target) = &target
+    {
+        match target.get(store) {
+            Ok(Some(answer)) => return Ok(Some(answer)),
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: Failed to read cached credential: {}", e),
+        }
+    }
+
+    if request.echo {
+        return prompter.prompt_text(CAPTION, &request.message);
+    }
+    let Some(result) =
+        prompter.prompt_password(CAPTION, &request.message, target.is_some(), false)?
+    else {
+        return Ok(None);
+    };
+    if result.save
+        && let Some(target) = &target
+        && let Err(e) = target.store(store, result.password.expose())
+    {
+        eprintln!("Warning: Failed to save credential: {}", e);
+    }
+    Ok(Some(result.password))
+}
+
+/// Sends `+<password>`, or `-` if the user cancelled.
+fn reply(socket: &Path, answer: Option<&Secret>) -> Result<()> {
+    let datagram = match answer {
+        Some(answer) => {
+            let mut datagram = Vec::with_capacity(answer.expose().len() + 1);
+            datagram.push(b'+');
+            datagram.extend_from_slice(answer.expose().as_bytes());
+            SecretBuf::from(datagram)
+        }
+        None => SecretBuf::from(vec![b'-']),
+    };
+    UnixDatagram::unbound()?
+        .send_to(&datagram, socket)
+        .with_context(|| format!("Failed to answer on {}", socket.display()))?;
+    Ok(())
+}
+
+/// The requests waiting in a directory, remembering which were answered.
+struct Agent {
+    dir: PathBuf,
+    answered: HashSet<PathBuf>,
+}
+
+impl Agent {
+    fn new(dir: &Path) -> Agent {
+        Agent {
+            dir: dir.to_path_buf(),
+            answered: HashSet::new(),
+        }
+    }
+
+    /// Answers every request not answered yet.
+    fn process(&mut self, store: &dyn CredentialStore, prompter: &dyn Prompter) -> Result<()> {
+        let mut pending = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path
+                .file_name()
+                .is_some_and(|name| name.as_bytes().starts_with(b"ask."))
+            {
+                pending.push(path);
+            }
+        }
+        // Requests are removed once answered
+        self.answered.retain(|path| pending.contains(path));
+        pending.sort();
+
+        for path in pending {
+            if self.answered.contains(&path) {
+                continue;
+            }
+            // Not fully written yet, or already gone
+            let Ok(text) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let request = match Request::parse(&text) {
+                Ok(request) => request,
+                Err(e) => {
+                    eprintln!("Warning: Ignoring {}: {}", path.display(), e);
+                    self.answered.insert(path);
+                    continue;
+                }
+            };
+            self.answered.insert(path);
+            if request.is_void() {
+                continue;
+            }
+
+            // Without a dialog the request is left to other agents
+            let result = answer(store, prompter, &request)
+                .and_then(|answer| reply(&request.socket, answer.as_ref()));
+            if let Err(e) = result {
+                eprintln!("Warning: {}", error::message(&e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Answers requests as they appear, until killed.
+pub fn run(store: &dyn CredentialStore, prompter: &dyn Prompter) -> Result<()> {
+    let dir = Path::new(ASK_PASSWORD_DIR);
+    let mut events = watch(dir)
+        .map_err(|e| Error::Unavailable(format!("Failed to watch {}: {}", dir.display(), e)))?;
+    let mut agent = Agent::new(dir);
+    agent.process(store, prompter)?;
+
+    // Each event is a reason to look again, no matter which file it is for
+    let mut buf = [0u8; 4096];
+    while events.read(&mut buf)? > 0 {
+        agent.process(store, prompter)?;
+    }
+    Ok(())
+}
+
+/// Returns an inotify descriptor reporting files written or moved into `dir`.
+fn watch(dir: &Path) -> io::Result<File> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: `fd` is a new descriptor owned by nothing else
+    let events = unsafe { File::from_raw_fd(fd) };
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+    if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::memory::MemoryStore;
+    use crate::dialog::scripted::ScriptedPrompter;
+    use std::env;
+
+    /// A directory with one request and the socket it is answered on.
+    struct Asking {
+        dir: PathBuf,
+        socket: UnixDatagram,
+    }
+
+    impl Asking {
+        fn new(name: &str, fields: &str) -> Asking {
+            let dir = env::temp_dir().join(format!(
+                "winaskpass-systemd-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let socket_path = dir.join("sck.1");
+            let socket = UnixDatagram::bind(&socket_path).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            fs::write(
+                dir.join("ask.1"),
+                format!(
+                    "[Ask]\nPID={}\nSocket={}\nMessage=Please enter passphrase for disk data:\nId=cryptsetup:/dev/sdb1\n{}",
+                    std::process::id(),
+                    socket_path.display(),
+                    fields
+                ),
+            )
+            .unwrap();
+            Asking { dir, socket }
+        }
+
+        /// Runs the agent once and returns the answer sent, if any.
+        fn answer(&self, store: &MemoryStore, prompter: &ScriptedPrompter) -> Option<String> {
+            Agent::new(&self.dir).process(store, prompter).unwrap();
+            let mut buf = [0u8; 256];
+            let len = self.socket.recv(&mut buf).ok()?;
+            Some(String::from_utf8(buf[..len].to_vec()).unwrap())
+        }
+    }
+
+    impl Drop for Asking {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = Request::parse(
+            "[Ask]\nPID=123\nSocket=/run/systemd/ask-password/sck.2f\nAcceptCached=1\nEcho=0\nNotAfter=1700000000\nMessage=Enter: x=y\nIcon=drive-harddisk\n",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Request {
+                socket: PathBuf::from("/run/systemd/ask-password/sck.2f"),
+                message: "Enter: x=y".to_string(),
+                id: None,
+                pid: Some(123),
+                not_after: 1700000000,
+                accept_cached: true,
+                echo: false,
+            }
+        );
+        assert!(Request::parse("[Ask]\nMessage=No socket\n").is_err());
+    }
+
+    #[test]
+    fn test_answer_and_save() {
+        let asking = Asking::new("answer", "AcceptCached=1\n");
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter {
+            password: Some(("hunter2", true)),
+            ..Default::default()
+        };
+        assert_eq!(
+            asking.answer(&store, &prompter).as_deref(),
+            Some("+hunter2")
+        );
+        assert_eq!(
+            prompter.password_calls.borrow()[0],
+            (
+                "Please enter passphrase for disk data:".to_string(),
+                true,
+                false
+            )
+        );
+        let cached = store.get("systemd:cryptsetup:/dev/sdb1").unwrap().unwrap();
+        assert_eq!(cached.secret.expose(), "hunter2");
+
+        // Answered from the cache the next time
+        let prompter = ScriptedPrompter::default();
+        assert_eq!(
+            asking.answer(&store, &prompter).as_deref(),
+            Some("+hunter2")
+        );
+        assert!(prompter.password_calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_cache_only_when_accepted() {
+        let asking = Asking::new("no-cache", "AcceptCached=0\n");
+        let store = MemoryStore::default();
+        store
+            .store(
+                "systemd:cryptsetup:/dev/sdb1",
+                "stale",
+                &Metadata::default(),
+            )
+            .unwrap();
+        let prompter = ScriptedPrompter::default();
+        assert_eq!(asking.answer(&store, &prompter).as_deref(), Some("-"));
+        assert_eq!(prompter.password_calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_echo_never_saved() {
+        let asking = Asking::new("echo", "Echo=1\n");
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter {
+            password: Some(("admin", true)),
+            ..Default::default()
+        };
+        assert_eq!(asking.answer(&store, &prompter).as_deref(), Some("+admin"));
+        assert!(!prompter.password_calls.borrow()[0].1);
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expired_request_ignored() {
+        let asking = Asking::new("expired", "NotAfter=1\n");
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter {
+            password: Some(("hunter2", false)),
+            ..Default::default()
+        };
+        assert_eq!(asking.answer(&store, &prompter), None);
+        assert!(prompter.password_calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_each_request_answered_once() {
+        let asking = Asking::new("once", "");
+        let store = MemoryStore::default();
+        let prompter = ScriptedPrompter {
+            password: Some(("hunter2", false)),
+            ..Default::default()
+        };
+        let mut agent = Agent::new(&asking.dir);
+        agent.process(&store, &prompter).unwrap();
+        agent.process(&store, &prompter).unwrap();
+        assert_eq!(prompter.password_calls.borrow().len(), 1);
+    }
+}